@@ -1,29 +1,70 @@
 extern crate cc;
 
+use std::env;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 
 fn main() -> io::Result<()> {
+    let x265 = env::var_os("CARGO_FEATURE_X265").is_some();
+
     let mut files = Vec::new();
     files.extend(extract_files("c/libheif/libheif", LIBHEIF_SOURCES));
     files.extend(extract_files("c/libde265/libde265", LIBDE265_SOURCES));
 
+    if x265 {
+        files.extend(extract_files("c/libheif/libheif", LIBHEIF_X265_SOURCES));
+    }
+
     files.sort();
 
-    cc::Build::new()
-        .include("c/stubs")
+    let mut heif = cc::Build::new();
+    heif.include("c/stubs")
         .include("c/libheif")
         .include("c/libde265")
         .define("HAVE_LIBDE265", Some("1"))
         .define("HAVE_UNISTD_H", Some("1"))
         .define("HAVE_STDINT_H", Some("1"))
-        .define("HAVE_MALLOC_H", Some("1"))
-        .files(files)
+        .define("HAVE_MALLOC_H", Some("1"));
+
+    if x265 {
+        heif.include("c/stubs/x265")
+            .include("c/x265/source")
+            .define("HAVE_X265", Some("1"));
+    }
+
+    heif.files(files)
         .warnings(false)
         .cpp(true)
         .compile("heif-all");
 
+    // after heif-all, so the linker can resolve its references to x265
+    if x265 {
+        let mut files = Vec::new();
+        files.extend(extract_files("c/x265/source/common", X265_COMMON_SOURCES));
+        files.extend(extract_files("c/x265/source/encoder", X265_ENCODER_SOURCES));
+
+        files.sort();
+
+        // no ENABLE_ASSEMBLY or X265_ARCH_*, so only the portable C++ primitives are built
+        cc::Build::new()
+            .include("c/stubs/x265")
+            .include("c/x265/source")
+            .include("c/x265/source/common")
+            .include("c/x265/source/encoder")
+            .define("X265_NS", Some("x265"))
+            .define("X265_VERSION", Some("3.0"))
+            .define("X265_DEPTH", Some("8"))
+            .define("HIGH_BIT_DEPTH", Some("0"))
+            .define("EXPORT_C_API", Some("1"))
+            .define("HAVE_INT_TYPES_H", Some("1"))
+            .define("__STDC_LIMIT_MACROS", Some("1"))
+            .files(files)
+            .warnings(false)
+            .cpp(true)
+            .compile("x265");
+    }
+
     Ok(())
 }
 
@@ -57,6 +98,62 @@ logging.h
     heif_decoder_libde265.h
 ";
 
+// libheif/CMakeLists.txt, if(X265_FOUND)
+const LIBHEIF_X265_SOURCES: &str = "
+    heif_encoder_x265.h
+    heif_encoder_x265.cc
+";
+
+// x265/source/common/CMakeLists.txt, without assembly
+const X265_COMMON_SOURCES: &str = "
+    primitives.cpp primitives.h
+    pixel.cpp dct.cpp lowpassdct.cpp ipfilter.cpp intrapred.cpp loopfilter.cpp
+    constants.cpp constants.h
+    cpu.cpp cpu.h version.cpp
+    threading.cpp threading.h
+    threadpool.cpp threadpool.h
+    wavefront.h wavefront.cpp
+    md5.cpp md5.h
+    bitstream.h bitstream.cpp
+    yuv.cpp yuv.h
+    shortyuv.cpp shortyuv.h
+    picyuv.cpp picyuv.h
+    common.cpp common.h
+    param.cpp param.h
+    frame.cpp frame.h
+    framedata.cpp framedata.h
+    cudata.cpp cudata.h
+    slice.cpp slice.h
+    lowres.cpp lowres.h mv.h
+    piclist.cpp piclist.h
+    predict.cpp predict.h
+    scalinglist.cpp scalinglist.h
+    quant.cpp quant.h contexts.h
+    deblock.cpp deblock.h
+";
+
+// x265/source/encoder/CMakeLists.txt
+const X265_ENCODER_SOURCES: &str = "
+    analysis.cpp analysis.h
+    search.cpp search.h
+    bitcost.cpp bitcost.h rdcost.h
+    motion.cpp motion.h
+    slicetype.cpp slicetype.h
+    frameencoder.cpp frameencoder.h
+    framefilter.cpp framefilter.h
+    level.cpp level.h
+    nal.cpp nal.h
+    sei.cpp sei.h
+    sao.cpp sao.h
+    entropy.cpp entropy.h
+    dpb.cpp dpb.h
+    ratecontrol.cpp ratecontrol.h
+    reference.cpp reference.h
+    encoder.cpp encoder.h
+    api.cpp
+    weightPrediction.cpp
+";
+
 // libde265/CMakeLists.txt
 const LIBDE265_SOURCES: &str = "
   bitstream.cc
@@ -120,7 +217,7 @@ fn extract_files<P: AsRef<Path>>(base: P, paths: &str) -> Vec<PathBuf> {
     let mut files = Vec::new();
     for src in paths.split(|c: char| c.is_whitespace()) {
         let src = src.trim();
-        if !src.ends_with(".cc") {
+        if !src.ends_with(".cc") && !src.ends_with(".cpp") {
             continue;
         }
         let mut full_path = base.to_path_buf();
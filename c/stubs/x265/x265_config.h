@@ -0,0 +1,6 @@
+#ifndef X265_CONFIG_H
+#define X265_CONFIG_H
+
+#define X265_BUILD 169
+
+#endif
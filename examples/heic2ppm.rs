@@ -1,23 +1,21 @@
-#[macro_use]
-extern crate failure;
 extern crate libheif;
 
 use std::env;
+use std::error::Error;
 use std::fs;
 use std::io;
 use std::io::Write;
 use std::path;
 
-use failure::Error;
 use libheif::simple::Channel;
 use libheif::simple::Context;
 use libheif::simple::DecoderSettings;
 
-fn main() -> Result<(), Error> {
-    let usage = || format_err!("usage: FROM TO");
-    let mut ctx = Context::from_file(env::args_os().nth(1).ok_or_else(usage)?)?;
+fn main() -> Result<(), Box<dyn Error>> {
+    let usage = || "usage: FROM TO";
+    let ctx = Context::from_file(env::args_os().nth(1).ok_or_else(usage)?)?;
 
-    let mut handle = ctx.get_primary_image()?;
+    let handle = ctx.get_primary_image()?;
     let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
 
     let mut plane = image.plane(Channel::Interleaved)?;
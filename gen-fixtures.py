@@ -0,0 +1,348 @@
+#!/usr/bin/env python3
+"""Generate the test fixtures other than road.heic, through the system's libheif.
+
+    ./gen-fixtures.py [fixture.heic ...]
+
+libheif needs an HEVC encoder (x265); the committed files came from libheif 1.15.1
+with x265 3.5. Other versions may encode the pixels differently, but the tests only
+depend on what is described here.
+
+libheif can't write everything the tests need, so some files have boxes added after
+encoding, with the item locations fixed up to match.
+"""
+
+import ctypes
+import ctypes.util
+import struct
+import sys
+
+lib = ctypes.CDLL(ctypes.util.find_library('heif') or 'libheif.so.1')
+
+
+class Pointer(ctypes.c_void_p):
+    """Unlike `c_void_p`, isn't converted to an int, which would be passed as a (32-bit) `c_int`."""
+
+
+lib.heif_context_alloc.restype = Pointer
+lib.heif_image_get_plane.restype = ctypes.c_void_p
+lib.heif_image_get_plane.argtypes = [ctypes.c_void_p, ctypes.c_int, ctypes.POINTER(ctypes.c_int)]
+
+COLORSPACE_YCBCR, COLORSPACE_RGB, COLORSPACE_MONOCHROME = 0, 1, 2
+CHROMA_MONOCHROME, CHROMA_420, CHROMA_INTERLEAVED_RGB, CHROMA_INTERLEAVED_RGBA = 0, 1, 10, 11
+CHANNEL_Y, CHANNEL_CB, CHANNEL_CR, CHANNEL_INTERLEAVED = 0, 1, 2, 10
+COMPRESSION_HEVC = 1
+
+
+class HeifError(ctypes.Structure):
+    _fields_ = [('code', ctypes.c_int), ('subcode', ctypes.c_int), ('message', ctypes.c_char_p)]
+
+
+def call(name, *args):
+    fn = getattr(lib, name)
+    fn.restype = HeifError
+    err = fn(*args)
+    if err.code:
+        raise RuntimeError('%s: %s' % (name, err.message.decode()))
+
+
+def image(width, height, colorspace, chroma, planes):
+    """`planes` are (channel, width, height, bit depth, pixel), where `pixel(x, y)` gives the samples."""
+    img = ctypes.c_void_p()
+    call('heif_image_create', width, height, colorspace, chroma, ctypes.byref(img))
+    for channel, plane_width, plane_height, depth, pixel in planes:
+        call('heif_image_add_plane', img, channel, plane_width, plane_height, depth)
+        stride = ctypes.c_int()
+        data = lib.heif_image_get_plane(img, channel, ctypes.byref(stride))
+        sample = 'H' if depth > 8 else 'B'
+        for y in range(plane_height):
+            row = b''.join(struct.pack('=%d%s' % (len(p), sample), *p)
+                           for p in (pixel(x, y) for x in range(plane_width)))
+            ctypes.memmove(data + y * stride.value, row, len(row))
+    return img
+
+
+def gradient(width, height):
+    return image(width, height, COLORSPACE_RGB, CHROMA_INTERLEAVED_RGB, [
+        (CHANNEL_INTERLEAVED, width, height, 8, lambda x, y: (x * 4, y * 4, 0x80)),
+    ])
+
+
+def encoder(ctx, quality):
+    enc = ctypes.c_void_p()
+    call('heif_context_get_encoder_for_format', ctx, COMPRESSION_HEVC, ctypes.byref(enc))
+    call('heif_encoder_set_lossy_quality', enc, quality)
+    return enc
+
+
+def encode(ctx, img, quality):
+    handle = ctypes.c_void_p()
+    call('heif_context_encode_image', ctx, img, encoder(ctx, quality), None, ctypes.byref(handle))
+    return handle
+
+
+def alpha(path):
+    """64x64, RGBA, with the left half opaque, and the right transparent."""
+    ctx = lib.heif_context_alloc()
+    encode(ctx, image(64, 64, COLORSPACE_RGB, CHROMA_INTERLEAVED_RGBA, [
+        (CHANNEL_INTERLEAVED, 64, 64, 8, lambda x, y: (x * 4, y * 4, 128, 255 if x < 32 else 0)),
+    ]), 90)
+    call('heif_context_write_to_file', ctx, path)
+
+
+def ten_bit(path):
+    """64x64, YCbCr 4:2:0 at 10 bits per sample, a grey gradient from left to right."""
+    ctx = lib.heif_context_alloc()
+    encode(ctx, image(64, 64, COLORSPACE_YCBCR, CHROMA_420, [
+        (CHANNEL_Y, 64, 64, 10, lambda x, y: (x * 16,)),
+        (CHANNEL_CB, 32, 32, 10, lambda x, y: (512,)),
+        (CHANNEL_CR, 32, 32, 10, lambda x, y: (512,)),
+    ]), 90)
+    call('heif_context_write_to_file', ctx, path)
+
+
+def rotated(path):
+    """A 64x48 gradient, and an `irot` telling readers to turn it 90 degrees clockwise."""
+    ctx = lib.heif_context_alloc()
+    encode(ctx, gradient(64, 48), 50)
+    call('heif_context_write_to_file', ctx, path)
+    item, _ = items(path)
+
+    def edit(meta):
+        # the angle is in anti-clockwise quarter turns; it transforms, so is essential
+        add_property(meta, item, b'irot', b'\x03', essential=True)
+
+    edit_meta(path, edit)
+
+
+def burst(path):
+    """Three top level gradients, 64x48, 32x24 (the primary image), and 48x32, in that order."""
+    ctx = lib.heif_context_alloc()
+    images = [encode(ctx, gradient(width, height), 50) for width, height in [(64, 48), (32, 24), (48, 32)]]
+    call('heif_context_set_primary_image', ctx, images[1])
+    call('heif_context_write_to_file', ctx, path)
+
+
+def thumbnail(path):
+    """A 64x48 gradient with a 32x24 thumbnail, whose data is stored first."""
+    ctx = lib.heif_context_alloc()
+    small = encode(ctx, gradient(32, 24), 50)
+    master = encode(ctx, gradient(64, 48), 50)
+    # the header has named these both ways around, but the thumbnail has always come first
+    call('heif_context_assign_thumbnail', ctx, small, master)
+    call('heif_context_set_primary_image', ctx, master)
+    call('heif_context_write_to_file', ctx, path)
+
+
+EXIF = b'Exif\0\0MM\0*\0\0\0\x08\0\0\0\0\0\0'
+XMP = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'><rdf:RDF/></x:xmpmeta>"
+
+
+def metadata(path):
+    """A 64x48 gradient with an (empty) Exif block, and some XMP."""
+    ctx = lib.heif_context_alloc()
+    master = encode(ctx, gradient(64, 48), 50)
+    call('heif_context_add_exif_metadata', ctx, master, EXIF, len(EXIF))
+    call('heif_context_add_XMP_metadata', ctx, master, XMP, len(XMP))
+    call('heif_context_write_to_file', ctx, path)
+
+
+def depth(path):
+    """A 64x48 gradient, with a depth map of the same size (uniform Z, z_near 1.5,
+    z_far 100, no d_min, d_max 2, and disparity reference view 1)."""
+    ctx = lib.heif_context_alloc()
+    depth_map = image(64, 48, COLORSPACE_MONOCHROME, CHROMA_MONOCHROME, [
+        (CHANNEL_Y, 64, 48, 8, lambda x, y: (x * 4,)),
+    ])
+    encode(ctx, depth_map, 50)
+    call('heif_context_set_primary_image', ctx, encode(ctx, gradient(64, 48), 50))
+    call('heif_context_write_to_file', ctx, path)
+    master_item, (depth_item,) = items(path)
+
+    # a depth_representation_info SEI message (ISO/IEC 23008-2, F.14.2.19),
+    # each value as (sign, exponent, mantissa length, mantissa)
+    bits = Bits()
+    bits.put(1, 1)  # has z_near
+    bits.put(1, 1)  # has z_far
+    bits.put(0, 1)  # has d_min
+    bits.put(1, 1)  # has d_max
+    bits.uvlc(2)  # uniform Z
+    bits.uvlc(1)  # disparity reference view
+    bits.element(0, 31, 1, 1)  # 2^0 * (1 + 1/2) = 1.5
+    bits.element(0, 37, 4, 9)  # 2^6 * (1 + 9/16) = 100
+    bits.element(0, 32, 1, 0)  # 2^1 = 2
+    payload = bits.bytes()
+    nal = bytes([39 << 1, 1, 177, len(payload)]) + payload  # prefix SEI
+    # the subtype, as libheif reads it: the total length, then the length of the NAL unit
+    subtype = struct.pack('>II', 8 + len(nal), len(nal)) + nal
+
+    def edit(meta):
+        add_property(meta, depth_item, b'auxC', b'\0\0\0\0' + b'urn:mpeg:hevc:2015:auxid:2\0' + subtype)
+        add_reference(meta, b'auxl', depth_item, [master_item])
+
+    edit_meta(path, edit)
+
+
+class Bits:
+    def __init__(self):
+        self.bits = ''
+
+    def put(self, value, count):
+        self.bits += format(value, '0%db' % count)
+
+    def uvlc(self, value):
+        """Exp-Golomb coded."""
+        count = (value + 1).bit_length()
+        self.put(0, count - 1)
+        self.put(value + 1, count)
+
+    def element(self, sign, exponent, mantissa_len, mantissa):
+        self.put(sign, 1)
+        self.put(exponent, 7)
+        self.put(mantissa_len - 1, 5)
+        self.put(mantissa, mantissa_len)
+
+    def bytes(self):
+        bits = self.bits + '0' * (-len(self.bits) % 8)
+        return bytes(int(bits[i:i + 8], 2) for i in range(0, len(bits), 8))
+
+
+def items(path):
+    """The primary item in the file, and the other top level items, in order."""
+    ctx = lib.heif_context_alloc()
+    call('heif_context_read_from_file', ctx, path, None)
+    primary = ctypes.c_uint32()
+    call('heif_context_get_primary_image_ID', ctx, ctypes.byref(primary))
+    ids = (ctypes.c_uint32 * 16)()
+    count = lib.heif_context_get_list_of_top_level_image_IDs(ctx, ids, len(ids))
+    return primary.value, [i for i in ids[:count] if i != primary.value]
+
+
+def boxes(data):
+    """Split `data` into [type, payload] pairs; libheif only writes 32-bit sizes for these."""
+    out = []
+    while data:
+        size, kind = struct.unpack('>I4s', data[:8])
+        assert size >= 8, 'unsupported box size'
+        out.append([kind, data[8:size]])
+        data = data[size:]
+    return out
+
+
+def unboxes(pairs):
+    return b''.join(struct.pack('>I4s', 8 + len(payload), kind) + payload for kind, payload in pairs)
+
+
+def find(pairs, kind):
+    return next(pair for pair in pairs if pair[0] == kind)
+
+
+def edit_meta(path, edit):
+    """Let `edit` change the children of the `meta` box, then move the data that `iloc`
+    points at in the file by however much `meta` grew."""
+    with open(path, 'rb') as f:
+        top = boxes(f.read())
+    kinds = [kind for kind, _ in top]
+    assert kinds.index(b'meta') < kinds.index(b'mdat'), 'data must come after meta'
+
+    meta = find(top, b'meta')
+    children = boxes(meta[1][4:])
+    edit(children)
+    grown = len(meta[1][:4] + unboxes(children)) - len(meta[1])
+    iloc = find(children, b'iloc')
+    iloc[1] = shift_iloc(iloc[1], grown)
+    meta[1] = meta[1][:4] + unboxes(children)
+
+    with open(path, 'wb') as f:
+        f.write(unboxes(top))
+
+
+def add_property(meta, item, kind, payload, essential=False):
+    iprp = find(meta, b'iprp')
+    properties = boxes(iprp[1])
+    ipco = find(properties, b'ipco')
+    ipco_children = boxes(ipco[1])
+    ipco_children.append([kind, payload])
+    ipco[1] = unboxes(ipco_children)
+    ipma = find(properties, b'ipma')
+    ipma[1] = associate(ipma[1], item, len(ipco_children), essential)
+    iprp[1] = unboxes(properties)
+
+
+def associate(ipma, item, index, essential):
+    version, large_index = ipma[0], ipma[3] & 1
+    id_format = '>H' if version < 1 else '>I'
+    pos = 8
+    for _ in range(struct.unpack('>I', ipma[4:8])[0]):
+        (entry_item,) = struct.unpack_from(id_format, ipma, pos)
+        pos += struct.calcsize(id_format)
+        count = ipma[pos]
+        end = pos + 1 + count * (2 if large_index else 1)
+        if entry_item == item:
+            if large_index:
+                association = struct.pack('>H', essential << 15 | index)
+            else:
+                assert index < 0x80
+                association = bytes([essential << 7 | index])
+            return ipma[:pos] + bytes([count + 1]) + ipma[pos + 1:end] + association + ipma[end:]
+        pos = end
+    raise KeyError('no properties for item %d' % item)
+
+
+def add_reference(meta, kind, from_item, to_items):
+    kinds = [k for k, _ in meta]
+    if b'iref' not in kinds:
+        meta.insert(kinds.index(b'iprp'), [b'iref', b'\0\0\0\0'])
+    iref = find(meta, b'iref')
+    assert iref[1][0] == 0, 'only 16-bit item ids are supported'
+    iref[1] += unboxes([[kind, struct.pack('>HH%dH' % len(to_items), from_item, len(to_items), *to_items)]])
+
+
+def shift_iloc(iloc, delta):
+    """Move every extent stored in the file by `delta` bytes."""
+    version = iloc[0]
+    offset_size, length_size = iloc[4] >> 4, iloc[4] & 15
+    base_offset_size, index_size = iloc[5] >> 4, iloc[5] & 15 if version in (1, 2) else 0
+    iloc = bytearray(iloc)
+
+    def read(pos, size):
+        return int.from_bytes(iloc[pos:pos + size], 'big')
+
+    def shift(pos, size):
+        iloc[pos:pos + size] = (read(pos, size) + delta).to_bytes(size, 'big')
+
+    id_size = 2 if version < 2 else 4
+    pos = 6 + id_size
+    for _ in range(read(6, id_size)):
+        pos += id_size
+        construction_method = 0
+        if version in (1, 2):
+            construction_method = read(pos, 2) & 15
+            pos += 2
+        pos += 2  # data reference index
+        in_file = 0 == construction_method
+        if in_file and base_offset_size:
+            shift(pos, base_offset_size)
+        pos += base_offset_size
+        extents = read(pos, 2)
+        pos += 2
+        for _ in range(extents):
+            pos += index_size
+            if in_file and not base_offset_size:
+                shift(pos, offset_size)
+            pos += offset_size + length_size
+    return bytes(iloc)
+
+
+FIXTURES = {
+    'alpha.heic': alpha,
+    'ten-bit.heic': ten_bit,
+    'rotated.heic': rotated,
+    'burst.heic': burst,
+    'thumbnail.heic': thumbnail,
+    'metadata.heic': metadata,
+    'depth.heic': depth,
+}
+
+if __name__ == '__main__':
+    for name in sys.argv[1:] or FIXTURES:
+        FIXTURES[name](name.encode())
@@ -0,0 +1,306 @@
+use std::error;
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+
+use raw;
+
+/// Everything which can go wrong in this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// libheif reported a failure.
+    Heif(HeifError),
+    /// An encoder parameter was rejected.
+    Parameter(ParameterError),
+    /// A Rust reader or writer, handed to libheif, failed.
+    Io(io::Error),
+    /// A Rust reader or writer, handed to libheif, panicked; the panic message is kept.
+    CallbackPanicked { what: &'static str, message: String },
+    /// An argument could not be used, e.g. a buffer of the wrong size, or a path with a nul in.
+    InvalidArgument(String),
+    /// libheif returned nothing (usually a null pointer), without explaining why,
+    /// or returned a value which makes no sense, e.g. a negative width.
+    Unexpected(&'static str),
+}
+
+/// A failure reported by libheif.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeifError {
+    pub code: ErrorCode,
+    pub subcode: SubErrorCode,
+    /// The libheif function which failed.
+    pub location: &'static str,
+    pub message: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    InputDoesNotExist,
+    InvalidInput,
+    UnsupportedFiletype,
+    UnsupportedFeature,
+    UsageError,
+    MemoryAllocationError,
+    DecoderPluginError,
+    EncoderPluginError,
+    EncodingError,
+    Other(raw::heif_error_code),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SubErrorCode {
+    Unspecified,
+    EndOfData,
+    InvalidBoxSize,
+    NoFtypBox,
+    NoIdatBox,
+    NoMetaBox,
+    NoHdlrBox,
+    NoHvcCBox,
+    NoPitmBox,
+    NoIpcoBox,
+    NoIpmaBox,
+    NoIlocBox,
+    NoIinfBox,
+    NoIprpBox,
+    NoIrefBox,
+    NoPictHandler,
+    IpmaBoxReferencesNonexistingProperty,
+    NoPropertiesAssignedToItem,
+    NoItemData,
+    InvalidGridData,
+    MissingGridImages,
+    InvalidCleanAperture,
+    InvalidOverlayData,
+    OverlayImageOutsideOfCanvas,
+    AuxiliaryImageTypeUnspecified,
+    NoOrInvalidPrimaryItem,
+    NoInfeBox,
+    SecurityLimitExceeded,
+    NonexistingItemReferenced,
+    NullPointerArgument,
+    NonexistingImageChannelReferenced,
+    UnsupportedPluginVersion,
+    UnsupportedWriterVersion,
+    UnsupportedParameter,
+    InvalidParameterValue,
+    UnsupportedCodec,
+    UnsupportedImageType,
+    UnsupportedDataVersion,
+    UnsupportedColorConversion,
+    UnsupportedItemConstructionMethod,
+    CannotWriteOutputData,
+    Other(raw::heif_suberror_code),
+}
+
+impl fmt::Display for HeifError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:?}/{:?}: {}",
+            self.location, self.code, self.subcode, self.message
+        )
+    }
+}
+
+impl error::Error for HeifError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParameterError {
+    Unsupported(String),
+    InvalidValue {
+        name: String,
+        reason: String,
+    },
+    /// The parameter exists, but its type is newer than these bindings.
+    UnknownType {
+        name: String,
+        kind: raw::heif_encoder_parameter_type,
+    },
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterError::Unsupported(name) => {
+                write!(f, "unsupported encoder parameter: {:?}", name)
+            }
+            ParameterError::InvalidValue { name, reason } => {
+                write!(
+                    f,
+                    "invalid value for encoder parameter {:?}: {}",
+                    name, reason
+                )
+            }
+            ParameterError::UnknownType { name, kind } => {
+                write!(f, "encoder parameter {:?} has unknown type {}", name, kind)
+            }
+        }
+    }
+}
+
+impl error::Error for ParameterError {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Heif(e) => e.fmt(f),
+            Error::Parameter(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::CallbackPanicked { what, message } => {
+                write!(f, "{} panicked: {}", what, message)
+            }
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::Unexpected(location) => write!(f, "{} failed", location),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Heif(e) => Some(e),
+            Error::Parameter(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HeifError> for Error {
+    fn from(e: HeifError) -> Error {
+        Error::Heif(e)
+    }
+}
+
+impl From<ParameterError> for Error {
+    fn from(e: ParameterError) -> Error {
+        Error::Parameter(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Error {
+        Error::InvalidArgument(e.to_string())
+    }
+}
+
+impl ErrorCode {
+    pub(crate) fn from_native(native: raw::heif_error_code) -> ErrorCode {
+        match native {
+            raw::heif_error_code_heif_error_Input_does_not_exist => ErrorCode::InputDoesNotExist,
+            raw::heif_error_code_heif_error_Invalid_input => ErrorCode::InvalidInput,
+            raw::heif_error_code_heif_error_Unsupported_filetype => ErrorCode::UnsupportedFiletype,
+            raw::heif_error_code_heif_error_Unsupported_feature => ErrorCode::UnsupportedFeature,
+            raw::heif_error_code_heif_error_Usage_error => ErrorCode::UsageError,
+            raw::heif_error_code_heif_error_Memory_allocation_error => {
+                ErrorCode::MemoryAllocationError
+            }
+            raw::heif_error_code_heif_error_Decoder_plugin_error => ErrorCode::DecoderPluginError,
+            raw::heif_error_code_heif_error_Encoder_plugin_error => ErrorCode::EncoderPluginError,
+            raw::heif_error_code_heif_error_Encoding_error => ErrorCode::EncodingError,
+            other => ErrorCode::Other(other),
+        }
+    }
+}
+
+impl SubErrorCode {
+    pub(crate) fn from_native(native: raw::heif_suberror_code) -> SubErrorCode {
+        match native {
+            raw::heif_suberror_code_heif_suberror_Unspecified => SubErrorCode::Unspecified,
+            raw::heif_suberror_code_heif_suberror_End_of_data => SubErrorCode::EndOfData,
+            raw::heif_suberror_code_heif_suberror_Invalid_box_size => SubErrorCode::InvalidBoxSize,
+            raw::heif_suberror_code_heif_suberror_No_ftyp_box => SubErrorCode::NoFtypBox,
+            raw::heif_suberror_code_heif_suberror_No_idat_box => SubErrorCode::NoIdatBox,
+            raw::heif_suberror_code_heif_suberror_No_meta_box => SubErrorCode::NoMetaBox,
+            raw::heif_suberror_code_heif_suberror_No_hdlr_box => SubErrorCode::NoHdlrBox,
+            raw::heif_suberror_code_heif_suberror_No_hvcC_box => SubErrorCode::NoHvcCBox,
+            raw::heif_suberror_code_heif_suberror_No_pitm_box => SubErrorCode::NoPitmBox,
+            raw::heif_suberror_code_heif_suberror_No_ipco_box => SubErrorCode::NoIpcoBox,
+            raw::heif_suberror_code_heif_suberror_No_ipma_box => SubErrorCode::NoIpmaBox,
+            raw::heif_suberror_code_heif_suberror_No_iloc_box => SubErrorCode::NoIlocBox,
+            raw::heif_suberror_code_heif_suberror_No_iinf_box => SubErrorCode::NoIinfBox,
+            raw::heif_suberror_code_heif_suberror_No_iprp_box => SubErrorCode::NoIprpBox,
+            raw::heif_suberror_code_heif_suberror_No_iref_box => SubErrorCode::NoIrefBox,
+            raw::heif_suberror_code_heif_suberror_No_pict_handler => SubErrorCode::NoPictHandler,
+            raw::heif_suberror_code_heif_suberror_Ipma_box_references_nonexisting_property => {
+                SubErrorCode::IpmaBoxReferencesNonexistingProperty
+            }
+            raw::heif_suberror_code_heif_suberror_No_properties_assigned_to_item => {
+                SubErrorCode::NoPropertiesAssignedToItem
+            }
+            raw::heif_suberror_code_heif_suberror_No_item_data => SubErrorCode::NoItemData,
+            raw::heif_suberror_code_heif_suberror_Invalid_grid_data => {
+                SubErrorCode::InvalidGridData
+            }
+            raw::heif_suberror_code_heif_suberror_Missing_grid_images => {
+                SubErrorCode::MissingGridImages
+            }
+            raw::heif_suberror_code_heif_suberror_Invalid_clean_aperture => {
+                SubErrorCode::InvalidCleanAperture
+            }
+            raw::heif_suberror_code_heif_suberror_Invalid_overlay_data => {
+                SubErrorCode::InvalidOverlayData
+            }
+            raw::heif_suberror_code_heif_suberror_Overlay_image_outside_of_canvas => {
+                SubErrorCode::OverlayImageOutsideOfCanvas
+            }
+            raw::heif_suberror_code_heif_suberror_Auxiliary_image_type_unspecified => {
+                SubErrorCode::AuxiliaryImageTypeUnspecified
+            }
+            raw::heif_suberror_code_heif_suberror_No_or_invalid_primary_item => {
+                SubErrorCode::NoOrInvalidPrimaryItem
+            }
+            raw::heif_suberror_code_heif_suberror_No_infe_box => SubErrorCode::NoInfeBox,
+            raw::heif_suberror_code_heif_suberror_Security_limit_exceeded => {
+                SubErrorCode::SecurityLimitExceeded
+            }
+            raw::heif_suberror_code_heif_suberror_Nonexisting_item_referenced => {
+                SubErrorCode::NonexistingItemReferenced
+            }
+            raw::heif_suberror_code_heif_suberror_Null_pointer_argument => {
+                SubErrorCode::NullPointerArgument
+            }
+            raw::heif_suberror_code_heif_suberror_Nonexisting_image_channel_referenced => {
+                SubErrorCode::NonexistingImageChannelReferenced
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_plugin_version => {
+                SubErrorCode::UnsupportedPluginVersion
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_writer_version => {
+                SubErrorCode::UnsupportedWriterVersion
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_parameter => {
+                SubErrorCode::UnsupportedParameter
+            }
+            raw::heif_suberror_code_heif_suberror_Invalid_parameter_value => {
+                SubErrorCode::InvalidParameterValue
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_codec => {
+                SubErrorCode::UnsupportedCodec
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_image_type => {
+                SubErrorCode::UnsupportedImageType
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_data_version => {
+                SubErrorCode::UnsupportedDataVersion
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_color_conversion => {
+                SubErrorCode::UnsupportedColorConversion
+            }
+            raw::heif_suberror_code_heif_suberror_Unsupported_item_construction_method => {
+                SubErrorCode::UnsupportedItemConstructionMethod
+            }
+            raw::heif_suberror_code_heif_suberror_Cannot_write_output_data => {
+                SubErrorCode::CannotWriteOutputData
+            }
+            other => SubErrorCode::Other(other),
+        }
+    }
+}
@@ -1,8 +1,7 @@
 extern crate cast;
 #[macro_use]
-extern crate failure;
-#[macro_use]
 extern crate more_asserts;
 
+pub mod error;
 pub mod raw;
 pub mod simple;
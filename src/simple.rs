@@ -1,44 +1,138 @@
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::mem;
 use std::os;
 use std::os::unix::ffi::OsStrExt;
+use std::panic;
 use std::path::Path;
 use std::ptr;
+use std::ptr::NonNull;
 use std::slice;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::vec;
 
+use cast;
+use cast::i32;
+use cast::i64;
 use cast::isize;
+use cast::u64;
 use cast::usize;
-use failure::Error;
 
+use error::Error;
+use error::ErrorCode;
+use error::HeifError;
+use error::ParameterError;
+use error::SubErrorCode;
 use raw;
 
-pub struct Context {
+/// Cheap to clone; clones refer to the same underlying file.
+///
+/// # Threads
+///
+/// libheif doesn't support using a context from several threads at once, so every call
+/// involving a context, or an `ImageHandle` from it, holds a lock on it. `Context` and
+/// `ImageHandle` are `Send` and `Sync`, but work on a single context is serialised;
+/// open the file once per thread to decode in parallel. Decoded `Image`s don't refer
+/// back to their context, so can be used freely.
+#[derive(Clone)]
+pub struct Context<'d> {
     inner: *mut raw::heif_context,
+    owner: Arc<ContextOwner<'d>>,
 }
 
-pub struct ImageHandle<'c> {
-    ctx: &'c mut Context,
+/// Owns the native context, and anything libheif may still read from it,
+/// until the last `Context` or `ImageHandle` using it has gone.
+struct ContextOwner<'d> {
+    inner: *mut raw::heif_context,
+    /// Held for every call into libheif involving the context, or its handles.
+    lock: Mutex<()>,
+    data: PhantomData<&'d [u8]>,
+    /// Owned, from `Box::into_raw`. libheif reads through it, so it is only
+    /// freed after the context, in `drop`.
+    reader: Option<NonNull<Reader<'d>>>,
+}
+
+pub type ItemId = raw::heif_item_id;
+
+/// An image in a `Context`, which keeps the context alive, so can be stored independently of it.
+pub struct ImageHandle<'d> {
+    ctx: Arc<ContextOwner<'d>>,
     inner: *mut raw::heif_image_handle,
 }
 
-pub struct Image<'c: 'h, 'h> {
-    handle: &'h mut ImageHandle<'c>,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub id: ItemId,
+    /// The item type, e.g. `Exif` or `mime`.
+    pub item_type: String,
+    /// The MIME type of `mime` items, e.g. `application/rdf+xml` for XMP.
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DepthRepresentationInfo {
+    pub version: u8,
+    pub z_near: Option<f64>,
+    pub z_far: Option<f64>,
+    pub d_min: Option<f64>,
+    pub d_max: Option<f64>,
+    pub depth_representation_type: DepthRepresentationType,
+    pub disparity_reference_view: u32,
+    pub depth_nonlinear_representation_model: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DepthRepresentationType {
+    UniformInverseZ,
+    UniformDisparity,
+    UniformZ,
+    NonuniformDisparity,
+    Other(raw::heif_depth_representation_type),
+}
+
+pub struct TopLevelImages<'d> {
+    ctx: Context<'d>,
+    ids: vec::IntoIter<ItemId>,
+}
+
+/// Decoded (or to-be-encoded) pixels; owns its data, so is independent of any `Context`.
+pub struct Image {
     inner: *mut raw::heif_image,
 }
 
-pub struct Plane<'c: 'h, 'h: 'i, 'i> {
-    image: &'i mut Image<'c, 'h>,
+pub struct Plane<'i> {
+    image: &'i mut Image,
     channel: Channel,
+    /// The chroma of the whole image, which determines the layout of interleaved planes.
+    chroma: Chroma,
     width: usize,
     height: usize,
+    bits_per_pixel: usize,
 }
 
-pub struct Pixels<'c: 'h, 'h: 'i, 'i: 'p, 'p> {
-    plane: &'p Plane<'c, 'h, 'i>,
+pub struct Pixels<'i: 'p, 'p> {
+    plane: &'p Plane<'i>,
     stride: usize,
     data: *const u8,
 }
 
+/// Like `Pixels`, but allows editing; there can only be one for a `Plane` at a time.
+pub struct PixelsMut<'i: 'p, 'p> {
+    plane: &'p mut Plane<'i>,
+    stride: usize,
+    data: *mut u8,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DecoderSettings {
     pub chroma: Chroma,
@@ -78,95 +172,945 @@ pub enum ColourSpace {
     Other(raw::heif_colorspace),
 }
 
-impl Context {
-    fn alloc() -> Result<*mut raw::heif_context, Error> {
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompressionFormat {
+    Undefined,
+    Hevc,
+    Avc,
+    Jpeg,
+    Other(raw::heif_compression_format),
+}
+
+pub struct Encoder {
+    inner: *mut raw::heif_encoder,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncoderParameter {
+    pub name: String,
+    pub kind: EncoderParameterKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncoderParameterKind {
+    /// An inclusive range, if the encoder restricts the value.
+    Integer {
+        range: Option<(i32, i32)>,
+    },
+    Boolean,
+    /// The only permitted values, if the encoder restricts the value.
+    String {
+        valid_values: Option<Vec<String>>,
+    },
+    Other(raw::heif_encoder_parameter_type),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncoderParameterValue {
+    Integer(i32),
+    Boolean(bool),
+    String(String),
+}
+
+/// Describes an encoder built in to libheif; can be used to pick which `Encoder` to create.
+#[derive(Copy, Clone)]
+pub struct EncoderDescriptor {
+    inner: *const raw::heif_encoder_descriptor,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EncodingOptions {
+    pub save_alpha_channel: bool,
+}
+
+/// Owns a native `heif_encoding_options` for the duration of a call.
+struct NativeEncodingOptions {
+    inner: *mut raw::heif_encoding_options,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecodingOptions {
+    /// Return the image as coded, without applying its rotation, mirroring or cropping.
+    pub ignore_transformations: bool,
+}
+
+/// Owns a native `heif_decoding_options` for the duration of a call.
+struct NativeDecodingOptions {
+    inner: *mut raw::heif_decoding_options,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GrowStatus {
+    /// At least the requested number of bytes are now available.
+    SizeReached,
+    /// The data may still arrive, but not in time; libheif will fail the current operation.
+    Timeout,
+    /// The source is complete, and is shorter than requested.
+    SizeBeyondEof,
+}
+
+/// A source whose length may increase while it is being read, e.g. an upload in progress.
+pub trait GrowingReader: Read + Seek {
+    /// Wait until at least `target_size` bytes can be read, or give up.
+    fn wait_for_file_size(&mut self, target_size: u64) -> io::Result<GrowStatus>;
+}
+
+impl Context<'static> {
+    /// An empty context, for encoding new images into.
+    pub fn new() -> Result<Self, Error> {
+        Context::alloc(None)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let ctx = Context::alloc(None)?;
+        let filename = path_to_cstring(path.as_ref())?;
+
+        check_error("heif_context_read_from_file", unsafe {
+            raw::heif_context_read_from_file(ctx.inner, filename.as_ptr(), ::std::ptr::null())
+        })?;
+
+        Ok(ctx)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let ctx = Context::alloc(None)?;
+
+        check_error("heif_context_read_from_memory", unsafe {
+            raw::heif_context_read_from_memory(
+                ctx.inner,
+                bytes.as_ptr() as *const os::raw::c_void,
+                bytes.len(),
+                ptr::null(),
+            )
+        })?;
+
+        Ok(ctx)
+    }
+}
+
+impl<'d> Context<'d> {
+    fn alloc(reader: Option<Box<Reader<'d>>>) -> Result<Context<'d>, Error> {
         let ptr = unsafe { raw::heif_context_alloc() };
         if ptr.is_null() {
-            bail!("allocation failed");
+            return Err(Error::Unexpected("heif_context_alloc"));
         }
 
-        Ok(ptr)
+        Ok(Context {
+            inner: ptr,
+            owner: Arc::new(ContextOwner {
+                inner: ptr,
+                lock: Mutex::new(()),
+                data: PhantomData,
+                reader: reader
+                    .map(|reader| unsafe { NonNull::new_unchecked(Box::into_raw(reader)) }),
+            }),
+        })
     }
 
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let path = path.as_ref();
-        let ptr = Context::alloc()?;
-        let filename = CString::new(path.as_os_str().to_str().ok_or_else(|| {
-            format_err!("path contained unrepresentable characters: {:?}", path)
-        })?)?;
+    /// The `Context` borrows `bytes`, instead of taking a copy,
+    /// so cannot outlive them.
+    pub fn from_bytes_without_copy(bytes: &'d [u8]) -> Result<Context<'d>, Error> {
+        let ctx = Context::alloc(None)?;
 
-        check_error("heif_context_read_from_file", unsafe {
-            raw::heif_context_read_from_file(ptr, filename.as_ptr(), ::std::ptr::null())
+        check_error("heif_context_read_from_memory_without_copy", unsafe {
+            raw::heif_context_read_from_memory_without_copy(
+                ctx.inner,
+                bytes.as_ptr() as *const os::raw::c_void,
+                bytes.len(),
+                ptr::null(),
+            )
         })?;
 
-        Ok(Context { inner: ptr })
+        Ok(ctx)
     }
 
-    pub fn get_primary_image(&mut self) -> Result<ImageHandle, Error> {
+    /// libheif reads image data from `reader` lazily, so the `Context` takes ownership of it.
+    pub fn from_reader<R: Read + Seek + Send + 'd>(reader: R) -> Result<Context<'d>, Error> {
+        Context::from_growing_reader(Complete {
+            inner: reader,
+            len: None,
+        })
+    }
+
+    /// Like `from_reader`, but data is waited for as libheif needs it.
+    ///
+    /// libheif (as of 1.15) waits for the whole of the image data, the `mdat` box, before
+    /// the file opens, so e.g. a thumbnail can't be shown before the rest has arrived.
+    /// If `reader` times out while opening, the error is returned, and `reader` is dropped;
+    /// try again, with a new reader, once more data has arrived.
+    pub fn from_growing_reader<R: GrowingReader + Send + 'd>(
+        reader: R,
+    ) -> Result<Context<'d>, Error> {
+        let ctx = Context::alloc(Some(Box::new(Reader {
+            inner: Box::new(reader),
+            error: None,
+        })))?;
+        let userdata = ctx.owner.reader.expect("just provided").as_ptr() as *mut os::raw::c_void;
+
+        let err = unsafe {
+            raw::heif_context_read_from_reader(ctx.inner, &READER, userdata, ptr::null())
+        };
+
+        ctx.owner.take_reader_error()?;
+        check_error("heif_context_read_from_reader", err)?;
+
+        Ok(ctx)
+    }
+
+    pub fn get_primary_image(&self) -> Result<ImageHandle<'d>, Error> {
+        let _lock = self.owner.lock();
         let mut ptr = ::std::ptr::null_mut();
         check_error("heif_context_get_primary_image_handle", unsafe {
             raw::heif_context_get_primary_image_handle(self.inner, &mut ptr)
         })?;
 
         Ok(ImageHandle {
-            ctx: self,
+            ctx: self.owner.clone(),
+            inner: ptr,
+        })
+    }
+
+    pub fn primary_image_id(&self) -> Result<ItemId, Error> {
+        let _lock = self.owner.lock();
+        let mut id = 0;
+        check_error("heif_context_get_primary_image_ID", unsafe {
+            raw::heif_context_get_primary_image_ID(self.inner, &mut id)
+        })?;
+
+        Ok(id)
+    }
+
+    pub fn number_of_top_level_images(&self) -> Result<usize, Error> {
+        let _lock = self.owner.lock();
+        check_returned(
+            "heif_context_get_number_of_top_level_images",
+            usize(unsafe { raw::heif_context_get_number_of_top_level_images(self.inner) }),
+        )
+    }
+
+    pub fn top_level_image_ids(&self) -> Result<Vec<ItemId>, Error> {
+        let expected = self.number_of_top_level_images()?;
+        let _lock = self.owner.lock();
+        get_ids(
+            "heif_context_get_list_of_top_level_image_IDs",
+            expected,
+            |ids, count| unsafe {
+                raw::heif_context_get_list_of_top_level_image_IDs(self.inner, ids, count)
+            },
+        )
+    }
+
+    pub fn is_top_level_image_id(&self, id: ItemId) -> bool {
+        let _lock = self.owner.lock();
+        0 != unsafe { raw::heif_context_is_top_level_image_ID(self.inner, id) }
+    }
+
+    pub fn get_image(&self, id: ItemId) -> Result<ImageHandle<'d>, Error> {
+        let _lock = self.owner.lock();
+        let mut ptr = ::std::ptr::null_mut();
+        check_error("heif_context_get_image_handle", unsafe {
+            raw::heif_context_get_image_handle(self.inner, id, &mut ptr)
+        })?;
+
+        Ok(ImageHandle {
+            ctx: self.owner.clone(),
+            inner: ptr,
+        })
+    }
+
+    pub fn top_level_images(&self) -> Result<TopLevelImages<'d>, Error> {
+        Ok(TopLevelImages {
+            ctx: self.clone(),
+            ids: self.top_level_image_ids()?.into_iter(),
+        })
+    }
+
+    pub fn encoder_for_format(&self, format: CompressionFormat) -> Result<Encoder, Error> {
+        let _lock = self.owner.lock();
+        let mut ptr = ptr::null_mut();
+        check_error("heif_context_get_encoder_for_format", unsafe {
+            raw::heif_context_get_encoder_for_format(self.inner, format.to_native(), &mut ptr)
+        })?;
+
+        Ok(Encoder { inner: ptr })
+    }
+
+    /// Available encoders, most preferred first, optionally filtered by format and `id_name`.
+    pub fn encoder_descriptors(
+        &self,
+        format_filter: Option<CompressionFormat>,
+        name_filter: Option<&str>,
+    ) -> Result<Vec<EncoderDescriptor>, Error> {
+        let _lock = self.owner.lock();
+        let format_filter = format_filter.unwrap_or(CompressionFormat::Undefined);
+        let name_filter = name_filter.map(CString::new).transpose()?;
+
+        let mut descriptors = Vec::new();
+        let mut capacity = 8;
+        loop {
+            descriptors.resize(capacity, ptr::null());
+            let location = "heif_context_get_encoder_descriptors";
+            let count = check_returned(
+                location,
+                usize(unsafe {
+                    raw::heif_context_get_encoder_descriptors(
+                        self.inner,
+                        format_filter.to_native(),
+                        as_ptr_or_null(&name_filter),
+                        descriptors.as_mut_ptr(),
+                        check_returned(location, i32(capacity))?,
+                    )
+                }),
+            )?;
+
+            if count < capacity {
+                descriptors.truncate(count);
+                break;
+            }
+
+            capacity *= 2;
+        }
+
+        Ok(descriptors
+            .into_iter()
+            .map(|inner| EncoderDescriptor { inner })
+            .collect())
+    }
+
+    pub fn encoder(&self, descriptor: &EncoderDescriptor) -> Result<Encoder, Error> {
+        let _lock = self.owner.lock();
+        let mut ptr = ptr::null_mut();
+        check_error("heif_context_get_encoder", unsafe {
+            raw::heif_context_get_encoder(self.inner, descriptor.inner, &mut ptr)
+        })?;
+
+        Ok(Encoder { inner: ptr })
+    }
+
+    /// The first image encoded into a `Context` becomes its primary image.
+    pub fn encode_image(
+        &self,
+        image: &Image,
+        encoder: &mut Encoder,
+        options: &EncodingOptions,
+    ) -> Result<ImageHandle<'d>, Error> {
+        let _lock = self.owner.lock();
+        let options = options.to_native()?;
+        let mut ptr = ptr::null_mut();
+        check_error("heif_context_encode_image", unsafe {
+            raw::heif_context_encode_image(
+                self.inner,
+                image.inner,
+                encoder.inner,
+                options.inner,
+                &mut ptr,
+            )
+        })?;
+
+        Ok(ImageHandle {
+            ctx: self.owner.clone(),
             inner: ptr,
         })
     }
+
+    /// Scale `image` to fit within `bbox_size` and encode it as a thumbnail of `master`.
+    ///
+    /// `None` if libheif decided that no thumbnail was needed, as `image` already fits.
+    pub fn encode_thumbnail(
+        &self,
+        image: &Image,
+        master: &ImageHandle,
+        encoder: &mut Encoder,
+        options: &EncodingOptions,
+        bbox_size: usize,
+    ) -> Result<Option<ImageHandle<'d>>, Error> {
+        self.check_owns(master)?;
+        let _lock = self.owner.lock();
+        let options = options.to_native()?;
+        let mut ptr = ptr::null_mut();
+        check_error("heif_context_encode_thumbnail", unsafe {
+            raw::heif_context_encode_thumbnail(
+                self.inner,
+                image.inner,
+                master.inner,
+                encoder.inner,
+                options.inner,
+                check_argument("bbox_size", i32(bbox_size))?,
+                &mut ptr,
+            )
+        })?;
+
+        if ptr.is_null() {
+            return Ok(None);
+        }
+
+        Ok(Some(ImageHandle {
+            ctx: self.owner.clone(),
+            inner: ptr,
+        }))
+    }
+
+    /// Mark an already encoded image as a thumbnail of `master`.
+    pub fn assign_thumbnail(
+        &self,
+        thumbnail: &ImageHandle,
+        master: &ImageHandle,
+    ) -> Result<(), Error> {
+        self.check_owns(thumbnail)?;
+        self.check_owns(master)?;
+        let _lock = self.owner.lock();
+        Ok(check_error("heif_context_assign_thumbnail", unsafe {
+            raw::heif_context_assign_thumbnail(self.inner, thumbnail.inner, master.inner)
+        })?)
+    }
+
+    /// `data` starts with the TIFF header, optionally preceded by `Exif\0\0`.
+    pub fn add_exif(&self, handle: &ImageHandle, data: &[u8]) -> Result<(), Error> {
+        self.check_owns(handle)?;
+        let _lock = self.owner.lock();
+        Ok(check_error("heif_context_add_exif_metadata", unsafe {
+            raw::heif_context_add_exif_metadata(
+                self.inner,
+                handle.inner,
+                data.as_ptr() as *const os::raw::c_void,
+                check_argument("data", i32(data.len()))?,
+            )
+        })?)
+    }
+
+    pub fn add_xmp(&self, handle: &ImageHandle, data: &[u8]) -> Result<(), Error> {
+        self.check_owns(handle)?;
+        let _lock = self.owner.lock();
+        Ok(check_error("heif_context_add_XMP_metadata", unsafe {
+            raw::heif_context_add_XMP_metadata(
+                self.inner,
+                handle.inner,
+                data.as_ptr() as *const os::raw::c_void,
+                check_argument("data", i32(data.len()))?,
+            )
+        })?)
+    }
+
+    /// Copy the Exif and XMP blocks of `from`, e.g. an image being re-encoded, onto `to`.
+    ///
+    /// libheif can only write these two kinds of metadata; other blocks are skipped.
+    pub fn copy_metadata(&self, from: &ImageHandle, to: &ImageHandle) -> Result<(), Error> {
+        for block in from.metadata(None)? {
+            match (block.item_type.as_str(), block.content_type.as_str()) {
+                // stored with a four byte offset to the TIFF header, which libheif recreates
+                ("Exif", _) if block.data.len() >= 4 => self.add_exif(to, &block.data[4..])?,
+                ("mime", "application/rdf+xml") => self.add_xmp(to, &block.data)?,
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode `image`, keeping the metadata of `source`, the image it was decoded from.
+    pub fn encode_image_preserving_metadata(
+        &self,
+        image: &Image,
+        source: &ImageHandle,
+        encoder: &mut Encoder,
+        options: &EncodingOptions,
+    ) -> Result<ImageHandle<'d>, Error> {
+        let handle = self.encode_image(image, encoder, options)?;
+        self.copy_metadata(source, &handle)?;
+        Ok(handle)
+    }
+
+    pub fn set_primary_image(&self, handle: &ImageHandle) -> Result<(), Error> {
+        self.check_owns(handle)?;
+        let _lock = self.owner.lock();
+        Ok(check_error("heif_context_set_primary_image", unsafe {
+            raw::heif_context_set_primary_image(self.inner, handle.inner)
+        })?)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let _lock = self.owner.lock();
+        let filename = path_to_cstring(path.as_ref())?;
+        Ok(check_error("heif_context_write_to_file", unsafe {
+            raw::heif_context_write_to_file(self.inner, filename.as_ptr())
+        })?)
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let _lock = self.owner.lock();
+        let mut writer = Writer {
+            inner: &mut writer,
+            error: None,
+        };
+
+        // unlike the reader, libheif is finished with this when heif_context_write returns
+        let mut table = raw::heif_writer {
+            writer_api_version: 1,
+            write: Some(writer_write),
+        };
+
+        let err = unsafe {
+            raw::heif_context_write(
+                self.inner,
+                &mut table,
+                &mut writer as *mut Writer as *mut os::raw::c_void,
+            )
+        };
+
+        if let Some(cause) = writer.error.take() {
+            return Err(cause);
+        }
+
+        check_error("heif_context_write", err)?;
+
+        Ok(writer.inner.flush()?)
+    }
+
+    /// libheif would happily mix up handles from different contexts; which we can't lock for.
+    fn check_owns(&self, handle: &ImageHandle) -> Result<(), Error> {
+        if !Arc::ptr_eq(&self.owner, &handle.ctx) {
+            return Err(Error::InvalidArgument(
+                "image handle belongs to a different context".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
-impl<'c> ImageHandle<'c> {
-    pub fn decode<'s>(&'s mut self, decode: DecoderSettings) -> Result<Image<'c, 's>, Error> {
+impl<'d> ContextOwner<'d> {
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        // nothing Rust can see is guarded, so a panic can't have left anything inconsistent
+        self.lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Report what went wrong in the reader, if anything, during the last call into libheif,
+    /// which also lets the reader be used again. Only call this with the lock held,
+    /// or before the context has been shared.
+    fn take_reader_error(&self) -> Result<(), Error> {
+        match self.reader {
+            Some(reader) => match unsafe { (*reader.as_ptr()).error.take() } {
+                Some(cause) => Err(cause),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'d> Iterator for TopLevelImages<'d> {
+    type Item = Result<ImageHandle<'d>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.ids.next().map(|id| self.ctx.get_image(id))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ids.size_hint()
+    }
+}
+
+impl<'d> ImageHandle<'d> {
+    pub fn decode(&self, decode: DecoderSettings) -> Result<Image, Error> {
+        self.decode_with_options(decode, &DecodingOptions::default())
+    }
+
+    pub fn decode_with_options(
+        &self,
+        decode: DecoderSettings,
+        options: &DecodingOptions,
+    ) -> Result<Image, Error> {
+        let _lock = self.ctx.lock();
+        let options = options.to_native()?;
         let mut ptr = ::std::ptr::null_mut();
 
-        check_error("heif_decode_image", unsafe {
+        let err = unsafe {
             raw::heif_decode_image(
                 self.inner,
                 &mut ptr,
                 decode.colour_space.to_native(),
                 decode.chroma.to_native(),
+                options.inner,
+            )
+        };
+
+        self.ctx.take_reader_error()?;
+        check_error("heif_decode_image", err)?;
+
+        Ok(Image { inner: ptr })
+    }
+
+    pub fn width(&self) -> Result<usize, Error> {
+        let _lock = self.ctx.lock();
+        check_returned(
+            "heif_image_handle_get_width",
+            usize(unsafe { raw::heif_image_handle_get_width(self.inner) }),
+        )
+    }
+
+    pub fn height(&self) -> Result<usize, Error> {
+        let _lock = self.ctx.lock();
+        check_returned(
+            "heif_image_handle_get_height",
+            usize(unsafe { raw::heif_image_handle_get_height(self.inner) }),
+        )
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        let _lock = self.ctx.lock();
+        0 != unsafe { raw::heif_image_handle_has_alpha_channel(self.inner) }
+    }
+
+    pub fn number_of_thumbnails(&self) -> Result<usize, Error> {
+        let _lock = self.ctx.lock();
+        check_returned(
+            "heif_image_handle_get_number_of_thumbnails",
+            usize(unsafe { raw::heif_image_handle_get_number_of_thumbnails(self.inner) }),
+        )
+    }
+
+    pub fn thumbnail_ids(&self) -> Result<Vec<ItemId>, Error> {
+        let expected = self.number_of_thumbnails()?;
+        let _lock = self.ctx.lock();
+        get_ids(
+            "heif_image_handle_get_list_of_thumbnail_IDs",
+            expected,
+            |ids, count| unsafe {
+                raw::heif_image_handle_get_list_of_thumbnail_IDs(self.inner, ids, count)
+            },
+        )
+    }
+
+    pub fn thumbnail(&self, id: ItemId) -> Result<ImageHandle<'d>, Error> {
+        let _lock = self.ctx.lock();
+        let mut ptr = ::std::ptr::null_mut();
+        check_error("heif_image_handle_get_thumbnail", unsafe {
+            raw::heif_image_handle_get_thumbnail(self.inner, id, &mut ptr)
+        })?;
+
+        Ok(ImageHandle {
+            ctx: self.ctx.clone(),
+            inner: ptr,
+        })
+    }
+
+    pub fn thumbnails(&self) -> Result<Vec<ImageHandle<'d>>, Error> {
+        self.thumbnail_ids()?
+            .into_iter()
+            .map(|id| self.thumbnail(id))
+            .collect()
+    }
+
+    pub fn has_depth_image(&self) -> bool {
+        let _lock = self.ctx.lock();
+        0 != unsafe { raw::heif_image_handle_has_depth_image(self.inner) }
+    }
+
+    pub fn number_of_depth_images(&self) -> Result<usize, Error> {
+        let _lock = self.ctx.lock();
+        check_returned(
+            "heif_image_handle_get_number_of_depth_images",
+            usize(unsafe { raw::heif_image_handle_get_number_of_depth_images(self.inner) }),
+        )
+    }
+
+    pub fn depth_image_ids(&self) -> Result<Vec<ItemId>, Error> {
+        let expected = self.number_of_depth_images()?;
+        let _lock = self.ctx.lock();
+        get_ids(
+            "heif_image_handle_get_list_of_depth_image_IDs",
+            expected,
+            |ids, count| unsafe {
+                raw::heif_image_handle_get_list_of_depth_image_IDs(self.inner, ids, count)
+            },
+        )
+    }
+
+    pub fn depth_image(&self, id: ItemId) -> Result<ImageHandle<'d>, Error> {
+        let _lock = self.ctx.lock();
+        let mut ptr = ::std::ptr::null_mut();
+        check_error("heif_image_handle_get_depth_image_handle", unsafe {
+            raw::heif_image_handle_get_depth_image_handle(self.inner, id, &mut ptr)
+        })?;
+
+        Ok(ImageHandle {
+            ctx: self.ctx.clone(),
+            inner: ptr,
+        })
+    }
+
+    pub fn depth_images(&self) -> Result<Vec<ImageHandle<'d>>, Error> {
+        self.depth_image_ids()?
+            .into_iter()
+            .map(|id| self.depth_image(id))
+            .collect()
+    }
+
+    /// Called on the main image, with the id of one of its depth images.
+    pub fn depth_representation_info(
+        &self,
+        depth_image_id: ItemId,
+    ) -> Option<DepthRepresentationInfo> {
+        let _lock = self.ctx.lock();
+        let mut ptr = ptr::null();
+        if 0 == unsafe {
+            raw::heif_image_handle_get_depth_image_representation_info(
+                self.inner,
+                depth_image_id,
+                &mut ptr,
+            )
+        } || ptr.is_null()
+        {
+            return None;
+        }
+
+        let info = DepthRepresentationInfo::from_native(unsafe { &*ptr });
+        unsafe { raw::heif_depth_representation_info_free(ptr) };
+        Some(info)
+    }
+
+    pub fn number_of_metadata_blocks(&self, type_filter: Option<&str>) -> Result<usize, Error> {
+        let _lock = self.ctx.lock();
+        let type_filter = type_filter.map(CString::new).transpose()?;
+        check_returned(
+            "heif_image_handle_get_number_of_metadata_blocks",
+            usize(unsafe {
+                raw::heif_image_handle_get_number_of_metadata_blocks(
+                    self.inner,
+                    as_ptr_or_null(&type_filter),
+                )
+            }),
+        )
+    }
+
+    /// `type_filter` is an item type, e.g. `Exif`; `None` lists every block.
+    pub fn metadata_block_ids(&self, type_filter: Option<&str>) -> Result<Vec<ItemId>, Error> {
+        let expected = self.number_of_metadata_blocks(type_filter)?;
+        let type_filter = type_filter.map(CString::new).transpose()?;
+        let _lock = self.ctx.lock();
+        get_ids(
+            "heif_image_handle_get_list_of_metadata_block_IDs",
+            expected,
+            |ids, count| unsafe {
+                raw::heif_image_handle_get_list_of_metadata_block_IDs(
+                    self.inner,
+                    as_ptr_or_null(&type_filter),
+                    ids,
+                    count,
+                )
+            },
+        )
+    }
+
+    pub fn metadata_block(&self, id: ItemId) -> Result<Metadata, Error> {
+        let _lock = self.ctx.lock();
+        let item_type = unsafe { raw::heif_image_handle_get_metadata_type(self.inner, id) };
+        if item_type.is_null() {
+            return Err(Error::InvalidArgument(format!(
+                "no such metadata block: {}",
+                id
+            )));
+        }
+
+        let content_type =
+            unsafe { raw::heif_image_handle_get_metadata_content_type(self.inner, id) };
+
+        let mut data =
+            vec![0u8; unsafe { raw::heif_image_handle_get_metadata_size(self.inner, id) }];
+        let err = unsafe {
+            raw::heif_image_handle_get_metadata(
+                self.inner,
+                id,
+                data.as_mut_ptr() as *mut os::raw::c_void,
+            )
+        };
+
+        self.ctx.take_reader_error()?;
+        check_error("heif_image_handle_get_metadata", err)?;
+
+        Ok(Metadata {
+            id,
+            item_type: from_string_lossy(item_type),
+            content_type: if content_type.is_null() {
+                String::new()
+            } else {
+                from_string_lossy(content_type)
+            },
+            data,
+        })
+    }
+
+    pub fn metadata(&self, type_filter: Option<&str>) -> Result<Vec<Metadata>, Error> {
+        self.metadata_block_ids(type_filter)?
+            .into_iter()
+            .map(|id| self.metadata_block(id))
+            .collect()
+    }
+}
+
+impl Image {
+    /// An image with no planes; add them with `add_plane`, then fill them with `pixels_mut`.
+    pub fn new(
+        width: usize,
+        height: usize,
+        colour_space: ColourSpace,
+        chroma: Chroma,
+    ) -> Result<Self, Error> {
+        let mut ptr = ptr::null_mut();
+        check_error("heif_image_create", unsafe {
+            raw::heif_image_create(
+                check_argument("width", i32(width))?,
+                check_argument("height", i32(height))?,
+                colour_space.to_native(),
+                chroma.to_native(),
+                &mut ptr,
+            )
+        })?;
+
+        Ok(Image { inner: ptr })
+    }
+
+    /// `bit_depth` is per component, e.g. 8 for an interleaved, 8-bit, RGB plane.
+    ///
+    /// Chroma planes of subsampled images are smaller than the image, e.g. half
+    /// the width and height for `Chroma::C420`.
+    pub fn add_plane(
+        &mut self,
+        channel: Channel,
+        width: usize,
+        height: usize,
+        bit_depth: u8,
+    ) -> Result<(), Error> {
+        Ok(check_error("heif_image_add_plane", unsafe {
+            raw::heif_image_add_plane(
+                self.inner,
+                channel.to_native(),
+                check_argument("width", i32(width))?,
+                check_argument("height", i32(height))?,
+                os::raw::c_int::from(bit_depth),
+            )
+        })?)
+    }
+
+    /// Copy a buffer of packed, 8-bit, RGB pixels, as might be encoded.
+    pub fn from_rgb8(data: &[u8], width: usize, height: usize) -> Result<Self, Error> {
+        Image::from_interleaved8(data, width, height, Chroma::InterleavedRgb, 3)
+    }
+
+    /// Copy a buffer of packed, 8-bit, RGBA pixels; the alpha is not premultiplied.
+    pub fn from_rgba8(data: &[u8], width: usize, height: usize) -> Result<Self, Error> {
+        Image::from_interleaved8(data, width, height, Chroma::InterleavedRgba, 4)
+    }
+
+    fn from_interleaved8(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        chroma: Chroma,
+        components: usize,
+    ) -> Result<Self, Error> {
+        if 0 == width || 0 == height {
+            return Err(Error::InvalidArgument(format!(
+                "empty image: {}x{}",
+                width, height
+            )));
+        }
+        let expected = width
+            .checked_mul(components)
+            .and_then(|row_bytes| row_bytes.checked_mul(height));
+        if expected != Some(data.len()) {
+            return Err(Error::InvalidArgument(format!(
+                "expected {}x{}x{} bytes, not {}",
+                width,
+                height,
+                components,
+                data.len()
+            )));
+        }
+        let row_bytes = width * components;
+
+        let mut image = Image::new(width, height, ColourSpace::Rgb, chroma)?;
+        image.add_plane(Channel::Interleaved, width, height, 8)?;
+        {
+            let mut plane = image.plane(Channel::Interleaved)?;
+            let mut pixels = plane.pixels_mut()?;
+            for (y, row) in data.chunks(row_bytes).enumerate() {
+                pixels.get_row_mut(y)[..row_bytes].copy_from_slice(row);
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// A new image, resized to exactly `width` x `height`, in the same colour space and chroma.
+    pub fn scale(&self, width: usize, height: usize) -> Result<Image, Error> {
+        let mut ptr = ptr::null_mut();
+        check_error("heif_image_scale_image", unsafe {
+            raw::heif_image_scale_image(
+                self.inner,
+                &mut ptr,
+                check_argument("width", i32(width))?,
+                check_argument("height", i32(height))?,
                 ptr::null(),
             )
         })?;
 
-        Ok(Image {
-            handle: self,
-            inner: ptr,
-        })
+        Ok(Image { inner: ptr })
     }
 
-    pub fn width(&self) -> Result<usize, Error> {
-        Ok(usize(unsafe {
-            raw::heif_image_handle_get_width(self.inner)
-        })?)
+    pub fn has_channel(&self, channel: Channel) -> bool {
+        0 != unsafe { raw::heif_image_has_channel(self.inner, channel.to_native()) }
     }
 
-    pub fn height(&self) -> Result<usize, Error> {
-        Ok(usize(unsafe {
-            raw::heif_image_handle_get_height(self.inner)
-        })?)
+    /// The storage used by each pixel of `channel`, e.g. 8 for `Y` of a typical image,
+    /// or 24 for an interleaved RGB image. Deeper channels use two bytes per component.
+    pub fn bits_per_pixel(&self, channel: Channel) -> Result<usize, Error> {
+        self.check_channel(channel)?;
+        check_returned(
+            "heif_image_get_bits_per_pixel",
+            usize(unsafe { raw::heif_image_get_bits_per_pixel(self.inner, channel.to_native()) }),
+        )
     }
-}
 
-impl<'c, 'h> Image<'c, 'h> {
-    pub fn plane<'s>(&'s mut self, channel: Channel) -> Result<Plane<'c, 'h, 's>, Error> {
+    pub fn plane(&mut self, channel: Channel) -> Result<Plane<'_>, Error> {
         let native_channel = channel.to_native();
 
-        if 0 == unsafe { raw::heif_image_has_channel(self.inner, native_channel) } {
-            bail!("no such channel {:?}", channel);
-        }
-        let width = usize(unsafe { raw::heif_image_get_width(self.inner, native_channel) })?;
-        let height = usize(unsafe { raw::heif_image_get_height(self.inner, native_channel) })?;
+        self.check_channel(channel)?;
+        let width = check_returned(
+            "heif_image_get_width",
+            usize(unsafe { raw::heif_image_get_width(self.inner, native_channel) }),
+        )?;
+        let height = check_returned(
+            "heif_image_get_height",
+            usize(unsafe { raw::heif_image_get_height(self.inner, native_channel) }),
+        )?;
+        let bits_per_pixel = self.bits_per_pixel(channel)?;
+        let chroma = Chroma::from_native(unsafe { raw::heif_image_get_chroma_format(self.inner) });
         Ok(Plane {
             image: self,
             channel,
+            chroma,
             width,
             height,
+            bits_per_pixel,
         })
     }
+
+    fn check_channel(&self, channel: Channel) -> Result<(), Error> {
+        if !self.has_channel(channel) {
+            return Err(Error::InvalidArgument(format!(
+                "no such channel {:?}",
+                channel
+            )));
+        }
+
+        Ok(())
+    }
 }
 
-impl<'c, 'h, 'i> Plane<'c, 'h, 'i> {
+impl<'i> Plane<'i> {
     pub fn width(&self) -> usize {
         self.width
     }
@@ -175,7 +1119,17 @@ impl<'c, 'h, 'i> Plane<'c, 'h, 'i> {
         self.height
     }
 
-    pub fn pixels<'s>(&'s self) -> Result<Pixels<'c, 'h, 'i, 's>, Error> {
+    /// See `Image::bits_per_pixel`.
+    pub fn bits_per_pixel(&self) -> usize {
+        self.bits_per_pixel
+    }
+
+    /// Storage used for each pixel, e.g. three bytes for interleaved, 8-bit, RGB.
+    fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel.div_ceil(8)
+    }
+
+    pub fn pixels<'s>(&'s self) -> Result<Pixels<'i, 's>, Error> {
         let mut stride: os::raw::c_int = 0;
 
         let ptr = unsafe {
@@ -187,10 +1141,10 @@ impl<'c, 'h, 'i> Plane<'c, 'h, 'i> {
         };
 
         if ptr.is_null() {
-            bail!("heif_image_get_plane_readonly failed");
+            return Err(Error::Unexpected("heif_image_get_plane_readonly"));
         }
-        let stride = usize(stride)?;
-        assert_le!(self.width(), stride);
+        let stride = check_returned("heif_image_get_plane_readonly", usize(stride))?;
+        assert_le!(self.width() * self.bytes_per_pixel(), stride);
 
         Ok(Pixels {
             plane: self,
@@ -198,9 +1152,29 @@ impl<'c, 'h, 'i> Plane<'c, 'h, 'i> {
             data: ptr,
         })
     }
+
+    pub fn pixels_mut<'s>(&'s mut self) -> Result<PixelsMut<'i, 's>, Error> {
+        let mut stride: os::raw::c_int = 0;
+
+        let ptr = unsafe {
+            raw::heif_image_get_plane(self.image.inner, self.channel.to_native(), &mut stride)
+        };
+
+        if ptr.is_null() {
+            return Err(Error::Unexpected("heif_image_get_plane"));
+        }
+        let stride = check_returned("heif_image_get_plane", usize(stride))?;
+        assert_le!(self.width() * self.bytes_per_pixel(), stride);
+
+        Ok(PixelsMut {
+            plane: self,
+            stride,
+            data: ptr,
+        })
+    }
 }
 
-impl<'c, 'h, 'i, 'p> Pixels<'c, 'h, 'i, 'p> {
+impl<'i, 'p> Pixels<'i, 'p> {
     pub fn get_row(&self, y: usize) -> &[u8] {
         assert_lt!(y, self.plane.height());
         unsafe {
@@ -212,12 +1186,378 @@ impl<'c, 'h, 'i, 'p> Pixels<'c, 'h, 'i, 'p> {
         }
     }
 
-    pub fn get_four(&self, x: usize, y: usize) -> u32 {
+    /// For interleaved RGB planes, i.e. those decoded with `Chroma::InterleavedRgb`.
+    pub fn get_rgb(&self, x: usize, y: usize) -> [u8; 3] {
+        assert_eq!(Chroma::InterleavedRgb, self.plane.chroma);
+        let pixel = self.get_pixel(x, y, 3);
+        [pixel[0], pixel[1], pixel[2]]
+    }
+
+    /// For interleaved RGBA planes, i.e. those decoded with `Chroma::InterleavedRgba`.
+    pub fn get_rgba(&self, x: usize, y: usize) -> [u8; 4] {
+        assert_eq!(Chroma::InterleavedRgba, self.plane.chroma);
+        let pixel = self.get_pixel(x, y, 4);
+        [pixel[0], pixel[1], pixel[2], pixel[3]]
+    }
+
+    /// For single component, 8-bit, planes, e.g. `Y` of a typical image.
+    pub fn get_u8(&self, x: usize, y: usize) -> u8 {
+        self.get_pixel(x, y, 1)[0]
+    }
+
+    /// For single component planes deeper than 8-bit, which libheif stores in native byte order.
+    pub fn get_u16(&self, x: usize, y: usize) -> u16 {
+        let pixel = self.get_pixel(x, y, 2);
+        u16::from_ne_bytes([pixel[0], pixel[1]])
+    }
+
+    /// For single component planes deeper than 8-bit, like `get_u16`;
+    /// unlike `get_row`, only `width` pixels are returned, without any padding.
+    pub fn get_row_u16(&self, y: usize) -> &[u16] {
+        self.check_bytes_per_pixel(2);
+        let row = self.get_row(y);
+        // libheif aligns planes, and their strides, far more than this
+        assert_eq!(0, row.as_ptr() as usize % mem::align_of::<u16>());
+        unsafe { slice::from_raw_parts(row.as_ptr() as *const u16, self.plane.width()) }
+    }
+
+    /// The storage of the pixel at (`x`, `y`), which must be `bytes` long.
+    fn get_pixel(&self, x: usize, y: usize, bytes: usize) -> &[u8] {
+        self.check_bytes_per_pixel(bytes);
         assert_lt!(x, self.plane.width());
+        &self.get_row(y)[x * bytes..(x + 1) * bytes]
+    }
+
+    fn check_bytes_per_pixel(&self, bytes: usize) {
+        assert_eq!(
+            bytes,
+            self.plane.bytes_per_pixel(),
+            "{:?} plane has {} bits per pixel",
+            self.plane.channel,
+            self.plane.bits_per_pixel
+        );
+    }
+}
+
+impl<'i, 'p> PixelsMut<'i, 'p> {
+    pub fn get_row(&self, y: usize) -> &[u8] {
+        assert_lt!(y, self.plane.height());
+        unsafe {
+            slice::from_raw_parts(
+                self.data
+                    .offset(isize(y * self.stride).expect("too big for isize")),
+                self.stride,
+            )
+        }
+    }
+
+    /// As with `get_row`, this includes any padding at the end of the row.
+    pub fn get_row_mut(&mut self, y: usize) -> &mut [u8] {
         assert_lt!(y, self.plane.height());
-        let channel_bytes: usize = unimplemented!("channel_bytes");
-        let off: usize = self.stride * y + x * channel_bytes;
-        unsafe { *(self.data.offset(isize(off).expect("too big for isize")) as *const u32) }
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.data
+                    .offset(isize(y * self.stride).expect("too big for isize")),
+                self.stride,
+            )
+        }
+    }
+}
+
+impl Encoder {
+    pub fn name(&self) -> String {
+        from_string_lossy(unsafe { raw::heif_encoder_get_name(self.inner) })
+    }
+
+    /// `quality` is from 0 (smallest output) to 100 (best lossy quality).
+    pub fn set_quality(&mut self, quality: u8) -> Result<(), Error> {
+        if quality > 100 {
+            return Err(ParameterError::InvalidValue {
+                name: "quality".to_string(),
+                reason: format!("{} is outside of 0..=100", quality),
+            }
+            .into());
+        }
+
+        check_parameter_error("heif_encoder_set_lossy_quality", "quality", unsafe {
+            raw::heif_encoder_set_lossy_quality(self.inner, os::raw::c_int::from(quality))
+        })
+    }
+
+    /// Even lossless, subsampled input, e.g. `Chroma::C420`, does not come back exactly,
+    /// and nor do strongly saturated colours, which clip when libheif converts RGB to YCbCr.
+    pub fn set_lossless(&mut self, lossless: bool) -> Result<(), Error> {
+        check_parameter_error("heif_encoder_set_lossless", "lossless", unsafe {
+            raw::heif_encoder_set_lossless(self.inner, lossless as os::raw::c_int)
+        })
+    }
+
+    pub fn parameters(&self) -> Result<Vec<EncoderParameter>, Error> {
+        let mut list = unsafe { raw::heif_encoder_list_parameters(self.inner) };
+        let mut parameters = Vec::new();
+        if list.is_null() {
+            return Ok(parameters);
+        }
+
+        while !unsafe { *list }.is_null() {
+            parameters.push(EncoderParameter::from_native(unsafe { *list })?);
+            list = unsafe { list.offset(1) };
+        }
+
+        Ok(parameters)
+    }
+
+    pub fn parameter(&self, name: &str) -> Result<EncoderParameter, Error> {
+        self.parameters()?
+            .into_iter()
+            .find(|parameter| parameter.name == name)
+            .ok_or_else(|| ParameterError::Unsupported(name.to_string()).into())
+    }
+
+    pub fn get_parameter(&self, name: &str) -> Result<EncoderParameterValue, Error> {
+        let parameter = self.parameter(name)?;
+        let c_name = CString::new(name)?;
+
+        Ok(match parameter.kind {
+            EncoderParameterKind::Integer { .. } => {
+                let mut value = 0;
+                check_parameter_error("heif_encoder_get_parameter_integer", name, unsafe {
+                    raw::heif_encoder_get_parameter_integer(self.inner, c_name.as_ptr(), &mut value)
+                })?;
+                EncoderParameterValue::Integer(value)
+            }
+            EncoderParameterKind::Boolean => {
+                let mut value = 0;
+                check_parameter_error("heif_encoder_get_parameter_boolean", name, unsafe {
+                    raw::heif_encoder_get_parameter_boolean(self.inner, c_name.as_ptr(), &mut value)
+                })?;
+                EncoderParameterValue::Boolean(0 != value)
+            }
+            EncoderParameterKind::String { .. } => {
+                let mut value = [0 as os::raw::c_char; 256];
+                check_parameter_error("heif_encoder_get_parameter_string", name, unsafe {
+                    raw::heif_encoder_get_parameter_string(
+                        self.inner,
+                        c_name.as_ptr(),
+                        value.as_mut_ptr(),
+                        i32(value.len()).expect("small buffer"),
+                    )
+                })?;
+                // ensure termination, in case the value was truncated
+                value[value.len() - 1] = 0;
+                EncoderParameterValue::String(from_string_lossy(value.as_ptr()))
+            }
+            EncoderParameterKind::Other(kind) => {
+                return Err(ParameterError::UnknownType {
+                    name: name.to_string(),
+                    kind,
+                }
+                .into())
+            }
+        })
+    }
+
+    /// The value is checked against the parameter's type and valid values before being set.
+    pub fn set_parameter(&mut self, name: &str, value: EncoderParameterValue) -> Result<(), Error> {
+        self.parameter(name)?.validate(&value)?;
+        let c_name = CString::new(name)?;
+
+        match value {
+            EncoderParameterValue::Integer(value) => {
+                check_parameter_error("heif_encoder_set_parameter_integer", name, unsafe {
+                    raw::heif_encoder_set_parameter_integer(self.inner, c_name.as_ptr(), value)
+                })
+            }
+            EncoderParameterValue::Boolean(value) => {
+                check_parameter_error("heif_encoder_set_parameter_boolean", name, unsafe {
+                    raw::heif_encoder_set_parameter_boolean(
+                        self.inner,
+                        c_name.as_ptr(),
+                        value as os::raw::c_int,
+                    )
+                })
+            }
+            EncoderParameterValue::String(ref value) => {
+                let value = CString::new(value.as_str())?;
+                check_parameter_error("heif_encoder_set_parameter_string", name, unsafe {
+                    raw::heif_encoder_set_parameter_string(
+                        self.inner,
+                        c_name.as_ptr(),
+                        value.as_ptr(),
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl EncoderParameter {
+    fn from_native(ptr: *const raw::heif_encoder_parameter) -> Result<EncoderParameter, Error> {
+        let name = from_string_lossy(unsafe { raw::heif_encoder_parameter_get_name(ptr) });
+
+        let kind = match unsafe { raw::heif_encoder_parameter_get_type(ptr) } {
+            raw::heif_encoder_parameter_type_heif_encoder_parameter_type_integer => {
+                let mut have_range = 0;
+                let mut minimum = 0;
+                let mut maximum = 0;
+                check_error("heif_encoder_parameter_get_valid_integer_range", unsafe {
+                    raw::heif_encoder_parameter_get_valid_integer_range(
+                        ptr,
+                        &mut have_range,
+                        &mut minimum,
+                        &mut maximum,
+                    )
+                })?;
+                EncoderParameterKind::Integer {
+                    range: if 0 != have_range {
+                        Some((minimum, maximum))
+                    } else {
+                        None
+                    },
+                }
+            }
+            raw::heif_encoder_parameter_type_heif_encoder_parameter_type_boolean => {
+                EncoderParameterKind::Boolean
+            }
+            raw::heif_encoder_parameter_type_heif_encoder_parameter_type_string => {
+                let mut list = ptr::null();
+                check_error("heif_encoder_parameter_get_valid_string_values", unsafe {
+                    raw::heif_encoder_parameter_get_valid_string_values(ptr, &mut list)
+                })?;
+
+                let valid_values = if list.is_null() {
+                    None
+                } else {
+                    let mut values = Vec::new();
+                    while !unsafe { *list }.is_null() {
+                        values.push(from_string_lossy(unsafe { *list }));
+                        list = unsafe { list.offset(1) };
+                    }
+                    Some(values)
+                };
+
+                EncoderParameterKind::String { valid_values }
+            }
+            other => EncoderParameterKind::Other(other),
+        };
+
+        Ok(EncoderParameter { name, kind })
+    }
+
+    pub fn validate(&self, value: &EncoderParameterValue) -> Result<(), ParameterError> {
+        let invalid = |reason: String| ParameterError::InvalidValue {
+            name: self.name.clone(),
+            reason,
+        };
+
+        match (&self.kind, value) {
+            (
+                EncoderParameterKind::Integer {
+                    range: Some((minimum, maximum)),
+                },
+                EncoderParameterValue::Integer(value),
+            ) => {
+                if value < minimum || value > maximum {
+                    return Err(invalid(format!(
+                        "{} is outside of {}..={}",
+                        value, minimum, maximum
+                    )));
+                }
+            }
+            (EncoderParameterKind::Integer { range: None }, EncoderParameterValue::Integer(_)) => {}
+            (EncoderParameterKind::Boolean, EncoderParameterValue::Boolean(_)) => {}
+            (
+                EncoderParameterKind::String {
+                    valid_values: Some(valid_values),
+                },
+                EncoderParameterValue::String(value),
+            ) => {
+                if !valid_values.contains(value) {
+                    return Err(invalid(format!(
+                        "{:?} is not one of {:?}",
+                        value, valid_values
+                    )));
+                }
+            }
+            (
+                EncoderParameterKind::String { valid_values: None },
+                EncoderParameterValue::String(_),
+            ) => {}
+            (kind, value) => {
+                return Err(invalid(format!("{:?} is not a {:?}", value, kind)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl EncoderDescriptor {
+    pub fn name(&self) -> String {
+        from_string_lossy(unsafe { raw::heif_encoder_descriptor_get_name(self.inner) })
+    }
+
+    /// A short, stable identifier, e.g. `x265`, suitable for `name_filter`.
+    pub fn id_name(&self) -> String {
+        from_string_lossy(unsafe { raw::heif_encoder_descriptor_get_id_name(self.inner) })
+    }
+
+    pub fn compression_format(&self) -> CompressionFormat {
+        CompressionFormat::from_native(unsafe {
+            raw::heif_encoder_descriptor_get_compression_format(self.inner)
+        })
+    }
+
+    pub fn supports_lossy_compression(&self) -> bool {
+        0 != unsafe { raw::heif_encoder_descriptor_supportes_lossy_compression(self.inner) }
+    }
+
+    pub fn supports_lossless_compression(&self) -> bool {
+        0 != unsafe { raw::heif_encoder_descriptor_supportes_lossless_compression(self.inner) }
+    }
+}
+
+impl fmt::Debug for EncoderDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncoderDescriptor")
+            .field("id_name", &self.id_name())
+            .field("name", &self.name())
+            .field("compression_format", &self.compression_format())
+            .finish()
+    }
+}
+
+impl Default for EncodingOptions {
+    fn default() -> EncodingOptions {
+        EncodingOptions {
+            save_alpha_channel: true,
+        }
+    }
+}
+
+impl EncodingOptions {
+    fn to_native(&self) -> Result<NativeEncodingOptions, Error> {
+        let ptr = unsafe { raw::heif_encoding_options_alloc() };
+        if ptr.is_null() {
+            return Err(Error::Unexpected("heif_encoding_options_alloc"));
+        }
+
+        unsafe { (*ptr).save_alpha_channel = self.save_alpha_channel as u8 };
+
+        Ok(NativeEncodingOptions { inner: ptr })
+    }
+}
+
+impl DecodingOptions {
+    fn to_native(&self) -> Result<NativeDecodingOptions, Error> {
+        let ptr = unsafe { raw::heif_decoding_options_alloc() };
+        if ptr.is_null() {
+            return Err(Error::Unexpected("heif_decoding_options_alloc"));
+        }
+
+        unsafe { (*ptr).ignore_transformations = self.ignore_transformations as u8 };
+
+        Ok(NativeDecodingOptions { inner: ptr })
     }
 }
 
@@ -228,47 +1568,399 @@ impl DecoderSettings {
             colour_space: ColourSpace::Rgb,
         }
     }
+
+    pub fn interleaved_rgba() -> DecoderSettings {
+        DecoderSettings {
+            chroma: Chroma::InterleavedRgba,
+            colour_space: ColourSpace::Rgb,
+        }
+    }
+
+    /// `interleaved_rgba` if the image has an alpha channel, `interleaved_rgb` otherwise.
+    pub fn interleaved_for(handle: &ImageHandle) -> DecoderSettings {
+        if handle.has_alpha() {
+            DecoderSettings::interleaved_rgba()
+        } else {
+            DecoderSettings::interleaved_rgb()
+        }
+    }
 }
 
 #[inline]
-fn check_error(location: &'static str, err: raw::heif_error) -> Result<(), Error> {
+fn check_error(location: &'static str, err: raw::heif_error) -> Result<(), HeifError> {
     if 0 == err.code {
         return Ok(());
     }
 
-    bail!(
-        "{}: {}/{}: {}",
+    Err(HeifError {
+        code: ErrorCode::from_native(err.code),
+        subcode: SubErrorCode::from_native(err.subcode),
         location,
-        err.code,
-        err.subcode,
-        from_string_lossy(err.message)
-    )
+        message: from_string_lossy(err.message),
+    })
+}
+
+/// A source which is already complete, so never needs to be waited for.
+struct Complete<R> {
+    inner: R,
+    /// Measured the first time it's needed.
+    len: Option<u64>,
+}
+
+impl<R: Read> Read for Complete<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for Complete<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read + Seek> GrowingReader for Complete<R> {
+    fn wait_for_file_size(&mut self, target_size: u64) -> io::Result<GrowStatus> {
+        let len = match self.len {
+            Some(len) => len,
+            None => {
+                let pos = self.stream_position()?;
+                let len = self.seek(SeekFrom::End(0))?;
+                self.seek(SeekFrom::Start(pos))?;
+                self.len = Some(len);
+                len
+            }
+        };
+
+        Ok(if target_size <= len {
+            GrowStatus::SizeReached
+        } else {
+            GrowStatus::SizeBeyondEof
+        })
+    }
+}
+
+struct Reader<'d> {
+    inner: Box<dyn GrowingReader + Send + 'd>,
+    error: Option<Error>,
+}
+
+static READER: raw::heif_reader = raw::heif_reader {
+    reader_api_version: 1,
+    get_position: Some(reader_get_position),
+    read: Some(reader_read),
+    seek: Some(reader_seek),
+    wait_for_file_size: Some(reader_wait_for_file_size),
+};
+
+impl<'d> Reader<'d> {
+    /// Run `f` against the wrapped reader, remembering the first failure (or panic),
+    /// as we are unable to pass anything more detailed than a status code back to libheif.
+    /// Later calls fail without touching the reader, until the failure has been reported.
+    fn call<T, F>(&mut self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut dyn GrowingReader) -> io::Result<T>,
+    {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let inner = &mut self.inner;
+        match catch_callback("reader", || f(&mut **inner)) {
+            Ok(val) => Some(val),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+/// Run a Rust callback on libheif's behalf; neither errors nor panics may cross back into C.
+fn catch_callback<T, F>(what: &'static str, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(val)) => Ok(val),
+        Ok(Err(e)) => Err(e.into()),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(Error::CallbackPanicked { what, message })
+        }
+    }
+}
+
+struct Writer<'w> {
+    inner: &'w mut dyn Write,
+    error: Option<Error>,
+}
+
+unsafe extern "C" fn writer_write(
+    _ctx: *mut raw::heif_context,
+    data: *const os::raw::c_void,
+    size: usize,
+    userdata: *mut os::raw::c_void,
+) -> raw::heif_error {
+    let writer = &mut *(userdata as *mut Writer);
+    let data = slice::from_raw_parts(data as *const u8, size);
+    let inner = &mut writer.inner;
+    match catch_callback("writer", || inner.write_all(data)) {
+        Ok(()) => raw::heif_error {
+            code: raw::heif_error_code_heif_error_Ok,
+            subcode: raw::heif_suberror_code_heif_suberror_Unspecified,
+            message: b"Success\0".as_ptr() as *const os::raw::c_char,
+        },
+        Err(e) => {
+            writer.error = Some(e);
+            raw::heif_error {
+                code: raw::heif_error_code_heif_error_Encoding_error,
+                subcode: raw::heif_suberror_code_heif_suberror_Cannot_write_output_data,
+                message: b"Cannot write output data\0".as_ptr() as *const os::raw::c_char,
+            }
+        }
+    }
+}
+
+unsafe fn reader_from<'a>(userdata: *mut os::raw::c_void) -> &'a mut Reader<'a> {
+    &mut *(userdata as *mut Reader)
+}
+
+unsafe extern "C" fn reader_get_position(userdata: *mut os::raw::c_void) -> i64 {
+    reader_from(userdata)
+        .call(|r| r.stream_position())
+        .and_then(|pos| i64(pos).ok())
+        .unwrap_or(-1)
+}
+
+unsafe extern "C" fn reader_read(
+    data: *mut os::raw::c_void,
+    size: usize,
+    userdata: *mut os::raw::c_void,
+) -> os::raw::c_int {
+    let buf = slice::from_raw_parts_mut(data as *mut u8, size);
+    match reader_from(userdata).call(|r| r.read_exact(buf)) {
+        Some(()) => 0,
+        None => 1,
+    }
+}
+
+unsafe extern "C" fn reader_seek(position: i64, userdata: *mut os::raw::c_void) -> os::raw::c_int {
+    let position = match u64(position) {
+        Ok(position) => position,
+        Err(_) => return 1,
+    };
+
+    match reader_from(userdata).call(|r| r.seek(SeekFrom::Start(position))) {
+        Some(_) => 0,
+        None => 1,
+    }
+}
+
+unsafe extern "C" fn reader_wait_for_file_size(
+    target_size: i64,
+    userdata: *mut os::raw::c_void,
+) -> raw::heif_reader_grow_status {
+    let target_size = match u64(target_size) {
+        Ok(target_size) => target_size,
+        Err(_) => return raw::heif_reader_grow_status_heif_reader_grow_status_size_beyond_eof,
+    };
+
+    match reader_from(userdata).call(|r| r.wait_for_file_size(target_size)) {
+        Some(status) => status.to_native(),
+        None => raw::heif_reader_grow_status_heif_reader_grow_status_size_beyond_eof,
+    }
+}
+
+/// Fetch a list of `expected` ids, using libheif's "fill this array" convention.
+fn get_ids<F>(location: &'static str, expected: usize, fill: F) -> Result<Vec<ItemId>, Error>
+where
+    F: FnOnce(*mut ItemId, os::raw::c_int) -> os::raw::c_int,
+{
+    let mut ids = vec![0; expected];
+    let capacity = check_returned(location, i32(ids.len()))?;
+    let count = check_returned(location, usize(fill(ids.as_mut_ptr(), capacity)))?;
+    ids.truncate(count);
+    Ok(ids)
+}
+
+/// libheif returned a value which doesn't fit, e.g. a negative count, so something is wrong with it.
+fn check_returned<T>(location: &'static str, value: Result<T, cast::Error>) -> Result<T, Error> {
+    value.map_err(|_| Error::Unexpected(location))
+}
+
+/// The caller's value doesn't fit in the type libheif takes, e.g. a width too large for an `int`.
+fn check_argument<T>(name: &'static str, value: Result<T, cast::Error>) -> Result<T, Error> {
+    value.map_err(|e| Error::InvalidArgument(format!("{}: {}", name, e)))
+}
+
+/// Like `check_error`, but distinguishes libheif rejecting a parameter, or its value.
+fn check_parameter_error(
+    location: &'static str,
+    name: &str,
+    err: raw::heif_error,
+) -> Result<(), Error> {
+    match err.subcode {
+        raw::heif_suberror_code_heif_suberror_Unsupported_parameter => {
+            Err(ParameterError::Unsupported(name.to_string()).into())
+        }
+        raw::heif_suberror_code_heif_suberror_Invalid_parameter_value => {
+            Err(ParameterError::InvalidValue {
+                name: name.to_string(),
+                reason: from_string_lossy(err.message),
+            }
+            .into())
+        }
+        _ => Ok(check_error(location, err)?),
+    }
+}
+
+fn path_to_cstring(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(path.as_os_str().to_str().ok_or_else(
+        || {
+            Error::InvalidArgument(format!(
+                "path contained unrepresentable characters: {:?}",
+                path
+            ))
+        },
+    )?)?)
+}
+
+fn as_ptr_or_null(string: &Option<CString>) -> *const os::raw::c_char {
+    string
+        .as_ref()
+        .map_or(ptr::null(), |string| string.as_ptr())
 }
 
 fn from_string_lossy(string: *const i8) -> String {
+    if string.is_null() {
+        return String::new();
+    }
+
     unsafe { CStr::from_ptr(string) }
         .to_string_lossy()
         .to_string()
 }
 
-impl Drop for Context {
+impl<'d> Drop for ContextOwner<'d> {
     fn drop(&mut self) {
         unsafe { raw::heif_context_free(self.inner) }
+        if let Some(reader) = self.reader {
+            drop(unsafe { Box::from_raw(reader.as_ptr()) });
+        }
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { raw::heif_encoder_release(self.inner) }
+    }
+}
+
+impl Drop for NativeEncodingOptions {
+    fn drop(&mut self) {
+        unsafe { raw::heif_encoding_options_free(self.inner) }
+    }
+}
+
+impl Drop for NativeDecodingOptions {
+    fn drop(&mut self) {
+        unsafe { raw::heif_decoding_options_free(self.inner) }
     }
 }
 
-impl<'c> Drop for ImageHandle<'c> {
+impl<'d> Drop for ImageHandle<'d> {
     fn drop(&mut self) {
+        let _lock = self.ctx.lock();
         unsafe { raw::heif_image_handle_release(self.inner) }
     }
 }
 
-impl<'c, 'h> Drop for Image<'c, 'h> {
+impl Drop for Image {
     fn drop(&mut self) {
         unsafe { raw::heif_image_release(self.inner) }
     }
 }
 
+// The native context, and anything reachable from it (including the reader), is only
+// touched with `lock` held, so may be shared between threads.
+unsafe impl<'d> Send for ContextOwner<'d> {}
+unsafe impl<'d> Sync for ContextOwner<'d> {}
+unsafe impl<'d> Send for Context<'d> {}
+unsafe impl<'d> Sync for Context<'d> {}
+unsafe impl<'d> Send for ImageHandle<'d> {}
+unsafe impl<'d> Sync for ImageHandle<'d> {}
+
+// An image owns its pixels, and is only modified through `&mut`.
+unsafe impl Send for Image {}
+unsafe impl Sync for Image {}
+unsafe impl<'i, 'p> Send for Pixels<'i, 'p> {}
+unsafe impl<'i, 'p> Sync for Pixels<'i, 'p> {}
+unsafe impl<'i, 'p> Send for PixelsMut<'i, 'p> {}
+unsafe impl<'i, 'p> Sync for PixelsMut<'i, 'p> {}
+
+// An encoder isn't tied to the thread which created it, but plugins needn't expect
+// concurrent calls, so it isn't `Sync`.
+unsafe impl Send for Encoder {}
+
+// Descriptors are static, immutable, data.
+unsafe impl Send for EncoderDescriptor {}
+unsafe impl Sync for EncoderDescriptor {}
+
+impl DepthRepresentationInfo {
+    fn from_native(info: &raw::heif_depth_representation_info) -> DepthRepresentationInfo {
+        let present = |has: u8, value: f64| if 0 != has { Some(value) } else { None };
+        let model = if info.depth_nonlinear_representation_model.is_null() {
+            Vec::new()
+        } else {
+            unsafe {
+                slice::from_raw_parts(
+                    info.depth_nonlinear_representation_model,
+                    info.depth_nonlinear_representation_model_size as usize,
+                )
+            }
+            .to_vec()
+        };
+
+        DepthRepresentationInfo {
+            version: info.version,
+            z_near: present(info.has_z_near, info.z_near),
+            z_far: present(info.has_z_far, info.z_far),
+            d_min: present(info.has_d_min, info.d_min),
+            d_max: present(info.has_d_max, info.d_max),
+            depth_representation_type: DepthRepresentationType::from_native(
+                info.depth_representation_type,
+            ),
+            disparity_reference_view: info.disparity_reference_view,
+            depth_nonlinear_representation_model: model,
+        }
+    }
+}
+
+impl DepthRepresentationType {
+    fn from_native(native: raw::heif_depth_representation_type) -> DepthRepresentationType {
+        match native {
+            raw::heif_depth_representation_type_heif_depth_representation_type_uniform_inverse_Z => {
+                DepthRepresentationType::UniformInverseZ
+            }
+            raw::heif_depth_representation_type_heif_depth_representation_type_uniform_disparity => {
+                DepthRepresentationType::UniformDisparity
+            }
+            raw::heif_depth_representation_type_heif_depth_representation_type_uniform_Z => {
+                DepthRepresentationType::UniformZ
+            }
+            raw::heif_depth_representation_type_heif_depth_representation_type_nonuniform_disparity => {
+                DepthRepresentationType::NonuniformDisparity
+            }
+            other => DepthRepresentationType::Other(other),
+        }
+    }
+}
+
 impl Channel {
     fn to_native(&self) -> raw::heif_channel {
         match self {
@@ -285,6 +1977,19 @@ impl Channel {
 }
 
 impl Chroma {
+    fn from_native(native: raw::heif_chroma) -> Chroma {
+        match native {
+            raw::heif_chroma_heif_chroma_undefined => Chroma::Undefined,
+            raw::heif_chroma_heif_chroma_monochrome => Chroma::Monochrome,
+            raw::heif_chroma_heif_chroma_420 => Chroma::C420,
+            raw::heif_chroma_heif_chroma_422 => Chroma::C422,
+            raw::heif_chroma_heif_chroma_444 => Chroma::C444,
+            raw::heif_chroma_heif_chroma_interleaved_RGB => Chroma::InterleavedRgb,
+            raw::heif_chroma_heif_chroma_interleaved_RGBA => Chroma::InterleavedRgba,
+            other => Chroma::Other(other),
+        }
+    }
+
     fn to_native(&self) -> raw::heif_chroma {
         match self {
             Chroma::Undefined => raw::heif_chroma_heif_chroma_undefined,
@@ -310,3 +2015,47 @@ impl ColourSpace {
         }
     }
 }
+
+impl CompressionFormat {
+    pub fn have_decoder(&self) -> bool {
+        0 != unsafe { raw::heif_have_decoder_for_format(self.to_native()) }
+    }
+
+    pub fn have_encoder(&self) -> bool {
+        0 != unsafe { raw::heif_have_encoder_for_format(self.to_native()) }
+    }
+
+    fn from_native(native: raw::heif_compression_format) -> CompressionFormat {
+        match native {
+            raw::heif_compression_format_heif_compression_undefined => CompressionFormat::Undefined,
+            raw::heif_compression_format_heif_compression_HEVC => CompressionFormat::Hevc,
+            raw::heif_compression_format_heif_compression_AVC => CompressionFormat::Avc,
+            raw::heif_compression_format_heif_compression_JPEG => CompressionFormat::Jpeg,
+            other => CompressionFormat::Other(other),
+        }
+    }
+
+    fn to_native(&self) -> raw::heif_compression_format {
+        match self {
+            CompressionFormat::Undefined => raw::heif_compression_format_heif_compression_undefined,
+            CompressionFormat::Hevc => raw::heif_compression_format_heif_compression_HEVC,
+            CompressionFormat::Avc => raw::heif_compression_format_heif_compression_AVC,
+            CompressionFormat::Jpeg => raw::heif_compression_format_heif_compression_JPEG,
+            CompressionFormat::Other(raw) => *raw,
+        }
+    }
+}
+
+impl GrowStatus {
+    fn to_native(&self) -> raw::heif_reader_grow_status {
+        match self {
+            GrowStatus::SizeReached => {
+                raw::heif_reader_grow_status_heif_reader_grow_status_size_reached
+            }
+            GrowStatus::Timeout => raw::heif_reader_grow_status_heif_reader_grow_status_timeout,
+            GrowStatus::SizeBeyondEof => {
+                raw::heif_reader_grow_status_heif_reader_grow_status_size_beyond_eof
+            }
+        }
+    }
+}
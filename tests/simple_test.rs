@@ -1,17 +1,48 @@
-extern crate failure;
 extern crate libheif;
 
-use failure::Error;
+#[cfg(feature = "x265")]
+use std::env;
+use std::fs;
+use std::io;
+#[cfg(feature = "x265")]
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use libheif::error::Error;
+use libheif::error::ErrorCode;
+use libheif::error::HeifError;
+use libheif::error::ParameterError;
+use libheif::error::SubErrorCode;
 use libheif::simple::Channel;
 use libheif::simple::Chroma;
 use libheif::simple::ColourSpace;
+#[cfg(feature = "x265")]
+use libheif::simple::CompressionFormat;
 use libheif::simple::Context;
 use libheif::simple::DecoderSettings;
+use libheif::simple::DecodingOptions;
+use libheif::simple::DepthRepresentationInfo;
+use libheif::simple::DepthRepresentationType;
+#[cfg(feature = "x265")]
+use libheif::simple::EncoderParameterKind;
+#[cfg(feature = "x265")]
+use libheif::simple::EncoderParameterValue;
+#[cfg(feature = "x265")]
+use libheif::simple::EncodingOptions;
+use libheif::simple::GrowStatus;
+use libheif::simple::GrowingReader;
+#[cfg(feature = "x265")]
+use libheif::simple::Image;
+use libheif::simple::ImageHandle;
 
 #[test]
 fn first_pixel() -> Result<(), Error> {
-    let mut ctx = Context::from_file("road.heic")?;
-    let mut handle = ctx.get_primary_image()?;
+    let ctx = Context::from_file("road.heic")?;
+    let handle = ctx.get_primary_image()?;
     {
         let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
         let plane = image.plane(Channel::Interleaved)?;
@@ -38,3 +69,1035 @@ fn first_pixel() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn from_bytes() -> Result<(), Error> {
+    let bytes = fs::read("road.heic")?;
+
+    let ctx = Context::from_bytes(&bytes)?;
+    let width = ctx.get_primary_image()?.width()?;
+
+    let borrowed = Context::from_bytes_without_copy(&bytes)?;
+    assert_eq!(width, borrowed.get_primary_image()?.width()?);
+
+    Ok(())
+}
+
+fn primary_rows(ctx: &Context) -> Result<Vec<Vec<u8>>, Error> {
+    let handle = ctx.get_primary_image()?;
+    let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
+    let plane = image.plane(Channel::Interleaved)?;
+    let pixels = plane.pixels()?;
+    // rows are padded out to the stride, and the padding is never written
+    let row_bytes = plane.width() * 3;
+    Ok((0..plane.height())
+        .map(|y| pixels.get_row(y)[..row_bytes].to_vec())
+        .collect())
+}
+
+#[test]
+fn from_reader() -> Result<(), Error> {
+    let expected = primary_rows(&Context::from_file("road.heic")?)?;
+    let ctx = Context::from_reader(fs::File::open("road.heic")?)?;
+    assert!(expected == primary_rows(&ctx)?);
+    Ok(())
+}
+
+struct PanickingReader;
+
+impl io::Read for PanickingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        panic!("no reading here")
+    }
+}
+
+impl io::Seek for PanickingReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        Ok(match pos {
+            io::SeekFrom::End(_) => 1024,
+            _ => 0,
+        })
+    }
+}
+
+#[test]
+fn from_reader_panic() {
+    let err = Context::from_reader(PanickingReader)
+        .err()
+        .expect("must fail");
+    assert!(err.to_string().contains("no reading here"), "{}", err);
+}
+
+struct Flaky {
+    data: io::Cursor<Vec<u8>>,
+    fail_next_wait: Arc<AtomicBool>,
+}
+
+impl io::Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl io::Seek for Flaky {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl GrowingReader for Flaky {
+    fn wait_for_file_size(&mut self, target_size: u64) -> io::Result<GrowStatus> {
+        if self.fail_next_wait.swap(false, Ordering::SeqCst) {
+            return Err(io::Error::other("flaky source"));
+        }
+        Ok(if target_size <= self.data.get_ref().len() as u64 {
+            GrowStatus::SizeReached
+        } else {
+            GrowStatus::SizeBeyondEof
+        })
+    }
+}
+
+#[test]
+fn reader_errors_after_open() -> Result<(), Error> {
+    let fail_next_wait = Arc::new(AtomicBool::new(false));
+    let ctx = Context::from_growing_reader(Flaky {
+        data: io::Cursor::new(fs::read("road.heic")?),
+        fail_next_wait: fail_next_wait.clone(),
+    })?;
+    let handle = ctx.get_primary_image()?;
+
+    fail_next_wait.store(true, Ordering::SeqCst);
+    match handle.decode(DecoderSettings::interleaved_rgb()) {
+        Err(Error::Io(ref e)) => assert_eq!("flaky source", e.to_string()),
+        Err(other) => panic!("unexpected error: {}", other),
+        Ok(_) => panic!("must fail"),
+    }
+
+    // the failure has been reported, so the reader is usable again
+    handle.decode(DecoderSettings::interleaved_rgb())?;
+
+    Ok(())
+}
+
+/// Only the first `available` bytes have arrived so far.
+struct Partial {
+    data: io::Cursor<Vec<u8>>,
+    available: Arc<AtomicUsize>,
+    timeouts: Arc<AtomicUsize>,
+}
+
+impl io::Read for Partial {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl io::Seek for Partial {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl GrowingReader for Partial {
+    fn wait_for_file_size(&mut self, target_size: u64) -> io::Result<GrowStatus> {
+        let available = self.available.load(Ordering::SeqCst) as u64;
+        Ok(if target_size > self.data.get_ref().len() as u64 {
+            GrowStatus::SizeBeyondEof
+        } else if target_size <= available {
+            GrowStatus::SizeReached
+        } else {
+            self.timeouts.fetch_add(1, Ordering::SeqCst);
+            GrowStatus::Timeout
+        })
+    }
+}
+
+/// libheif reports a timeout as running out of data; check that's really what happened.
+fn assert_timed_out<T>(result: Result<T, Error>, timeouts: &AtomicUsize) {
+    match result {
+        Err(Error::Heif(HeifError {
+            code: ErrorCode::InvalidInput,
+            subcode: SubErrorCode::EndOfData,
+            ..
+        })) => (),
+        Err(other) => panic!("unexpected error: {}", other),
+        Ok(_) => panic!("must time out"),
+    }
+    assert_ne!(0, timeouts.swap(0, Ordering::SeqCst));
+}
+
+#[test]
+fn from_growing_reader() -> Result<(), Error> {
+    let bytes = fs::read("thumbnail.heic")?;
+    // everything before the image data, which follows the `mdat` box's header
+    let header = 4 + bytes
+        .windows(4)
+        .position(|window| window == b"mdat")
+        .expect("has image data");
+
+    let available = Arc::new(AtomicUsize::new(16));
+    let timeouts = Arc::new(AtomicUsize::new(0));
+    let partial = || Partial {
+        data: io::Cursor::new(bytes.clone()),
+        available: available.clone(),
+        timeouts: timeouts.clone(),
+    };
+
+    // libheif may report this as the header being invalid, rather than incomplete
+    match Context::from_growing_reader(partial()) {
+        Err(Error::Heif(_)) => (),
+        Err(other) => panic!("unexpected error: {}", other),
+        Ok(_) => panic!("must time out"),
+    }
+    assert_ne!(0, timeouts.swap(0, Ordering::SeqCst));
+
+    // libheif waits for the whole of `mdat` before opening, not just the header
+    available.store(header, Ordering::SeqCst);
+    assert_timed_out(Context::from_growing_reader(partial()), &timeouts);
+
+    let ctx = loop {
+        available.fetch_add(16, Ordering::SeqCst);
+        match Context::from_growing_reader(partial()) {
+            Ok(ctx) => break ctx,
+            result => assert_timed_out(result, &timeouts),
+        }
+    };
+    assert!(available.load(Ordering::SeqCst) >= bytes.len());
+    assert_eq!(0, timeouts.load(Ordering::SeqCst));
+
+    let settings = DecoderSettings::interleaved_rgb();
+    let handle = ctx.get_primary_image()?;
+    let mut image = handle.decode(settings)?;
+    assert_eq!(64, image.plane(Channel::Interleaved)?.width());
+    let mut small = handle.thumbnails()?.remove(0).decode(settings)?;
+    assert_eq!(32, small.plane(Channel::Interleaved)?.width());
+
+    Ok(())
+}
+
+#[test]
+fn top_level_images() -> Result<(), Error> {
+    let ctx = Context::from_file("burst.heic")?;
+    let sizes = [(64, 48), (32, 24), (48, 32)];
+
+    let ids = ctx.top_level_image_ids()?;
+    assert_eq!(sizes.len(), ids.len());
+    assert_eq!(sizes.len(), ctx.number_of_top_level_images()?);
+    for (i, id) in ids.iter().enumerate() {
+        assert!(!ids[..i].contains(id), "{} is repeated", id);
+        assert!(ctx.is_top_level_image_id(*id));
+    }
+
+    for ((&id, handle), &size) in ids.iter().zip(ctx.top_level_images()?).zip(&sizes) {
+        let handle = handle?;
+        assert_eq!(size, (handle.width()?, handle.height()?));
+        let by_id = ctx.get_image(id)?;
+        assert_eq!(size, (by_id.width()?, by_id.height()?));
+    }
+    assert_eq!(sizes.len(), ctx.top_level_images()?.count());
+
+    assert_eq!(ids[1], ctx.primary_image_id()?);
+    let primary = ctx.get_primary_image()?;
+    assert_eq!((32, 24), (primary.width()?, primary.height()?));
+
+    Ok(())
+}
+
+#[test]
+fn thumbnails() -> Result<(), Error> {
+    let ctx = Context::from_file("thumbnail.heic")?;
+    let handle = ctx.get_primary_image()?;
+
+    assert_eq!(1, handle.number_of_thumbnails()?);
+    let ids = handle.thumbnail_ids()?;
+    assert_eq!(1, ids.len());
+
+    let thumbnails = handle.thumbnails()?;
+    assert_eq!(1, thumbnails.len());
+    assert_eq!((32, 24), (thumbnails[0].width()?, thumbnails[0].height()?));
+
+    let thumbnail = handle.thumbnail(ids[0])?;
+    assert_eq!(32, thumbnail.width()?);
+    let mut image = thumbnail.decode(DecoderSettings::interleaved_rgb())?;
+    let plane = image.plane(Channel::Interleaved)?;
+    assert_eq!((32, 24), (plane.width(), plane.height()));
+
+    Ok(())
+}
+
+#[test]
+fn metadata() -> Result<(), Error> {
+    let ctx = Context::from_file("metadata.heic")?;
+    let handle = ctx.get_primary_image()?;
+
+    assert_eq!(2, handle.number_of_metadata_blocks(None)?);
+    let all = handle.metadata(None)?;
+    assert_eq!(2, all.len());
+
+    let exif = handle.metadata(Some("Exif"))?;
+    assert_eq!(1, exif.len());
+    assert_eq!("Exif", exif[0].item_type);
+    assert_eq!("", exif[0].content_type);
+    // the offset of the TIFF header, then the block as it was given to libheif
+    assert_eq!(
+        &b"\0\0\0\x06Exif\0\0MM\0*\0\0\0\x08\0\0\0\0\0\0"[..],
+        &exif[0].data[..]
+    );
+
+    let xmp = handle.metadata(Some("mime"))?;
+    assert_eq!(1, xmp.len());
+    assert_eq!("mime", xmp[0].item_type);
+    assert_eq!("application/rdf+xml", xmp[0].content_type);
+    assert_eq!(
+        &b"<x:xmpmeta xmlns:x='adobe:ns:meta/'><rdf:RDF/></x:xmpmeta>"[..],
+        &xmp[0].data[..]
+    );
+
+    assert_eq!(vec![exif[0].clone(), xmp[0].clone()], all);
+    assert_eq!(xmp[0], handle.metadata_block(xmp[0].id)?);
+
+    Ok(())
+}
+
+#[test]
+fn depth_images() -> Result<(), Error> {
+    let ctx = Context::from_file("depth.heic")?;
+    let handle = ctx.get_primary_image()?;
+
+    assert!(handle.has_depth_image());
+    let ids = handle.depth_image_ids()?;
+    assert_eq!(1, ids.len());
+    assert_eq!(1, handle.depth_images()?.len());
+
+    let depth = handle.depth_image(ids[0])?;
+    assert_eq!((64, 48), (depth.width()?, depth.height()?));
+
+    let info = handle
+        .depth_representation_info(ids[0])
+        .expect("depth.heic has representation info");
+    assert_eq!(
+        DepthRepresentationInfo {
+            version: 1,
+            z_near: Some(1.5),
+            z_far: Some(100.),
+            d_min: None,
+            d_max: Some(2.),
+            depth_representation_type: DepthRepresentationType::UniformZ,
+            disparity_reference_view: 1,
+            depth_nonlinear_representation_model: Vec::new(),
+        },
+        info
+    );
+
+    assert!(!Context::from_file("road.heic")?
+        .get_primary_image()?
+        .has_depth_image());
+
+    Ok(())
+}
+
+#[test]
+fn alpha() -> Result<(), Error> {
+    assert!(!Context::from_file("road.heic")?
+        .get_primary_image()?
+        .has_alpha());
+
+    let ctx = Context::from_file("alpha.heic")?;
+    let handle = ctx.get_primary_image()?;
+    assert!(handle.has_alpha());
+
+    let settings = DecoderSettings::interleaved_for(&handle);
+    assert_eq!(DecoderSettings::interleaved_rgba(), settings);
+    {
+        let mut image = handle.decode(settings)?;
+        let plane = image.plane(Channel::Interleaved)?;
+        let pixels = plane.pixels()?;
+        let row = pixels.get_row(0);
+        assert_eq!(0xff, row[3]);
+        assert_eq!(0x00, row[(plane.width() - 1) * 4 + 3]);
+    }
+
+    {
+        let mut image = handle.decode(DecoderSettings {
+            chroma: Chroma::C420,
+            colour_space: ColourSpace::YCbCr,
+        })?;
+        let plane = image.plane(Channel::Alpha)?;
+        let pixels = plane.pixels()?;
+        let row = pixels.get_row(0);
+        assert_eq!(0xff, row[0]);
+        assert_eq!(0x00, row[plane.width() - 1]);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "x265")]
+fn gradient(width: usize, height: usize) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            rgb.extend_from_slice(&[(x * 4) as u8, (y * 4) as u8, 0x80]);
+        }
+    }
+    rgb
+}
+
+#[cfg(feature = "x265")]
+fn encode_gradient(width: usize, height: usize) -> Result<Context<'static>, Error> {
+    let image = Image::from_rgb8(&gradient(width, height), width, height)?;
+
+    let ctx = Context::new()?;
+    {
+        let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+        assert!(!encoder.name().is_empty());
+        let handle = ctx.encode_image(&image, &mut encoder, &EncodingOptions::default())?;
+        ctx.set_primary_image(&handle)?;
+    }
+    Ok(ctx)
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn encode() -> Result<(), Error> {
+    let ctx = encode_gradient(64, 48)?;
+
+    let path = env::temp_dir().join(format!("libheif-rs-encode-{}.heic", process::id()));
+    ctx.write_to_file(&path)?;
+
+    let written = Context::from_file(&path)?;
+    let primary = written.get_primary_image()?;
+    assert_eq!(64, primary.width()?);
+    assert_eq!(48, primary.height()?);
+
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(feature = "x265")]
+struct BrokenWriter;
+
+#[cfg(feature = "x265")]
+impl io::Write for BrokenWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk on fire"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn write_to() -> Result<(), Error> {
+    let ctx = encode_gradient(64, 48)?;
+
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+    assert_eq!(
+        48,
+        Context::from_bytes(&written)?
+            .get_primary_image()?
+            .height()?
+    );
+
+    let err = ctx.write_to(BrokenWriter).expect_err("must fail");
+    assert!(err.to_string().contains("disk on fire"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn encoder_descriptors() -> Result<(), Error> {
+    assert!(CompressionFormat::Hevc.have_decoder());
+    assert!(CompressionFormat::Hevc.have_encoder());
+
+    let ctx = Context::new()?;
+    let hevc = ctx.encoder_descriptors(Some(CompressionFormat::Hevc), None)?;
+    assert!(!hevc.is_empty());
+
+    for descriptor in &hevc {
+        assert_eq!(CompressionFormat::Hevc, descriptor.compression_format());
+
+        let by_name = ctx.encoder_descriptors(None, Some(&descriptor.id_name()))?;
+        assert_eq!(1, by_name.len());
+        assert!(!ctx.encoder(descriptor)?.name().is_empty());
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn encoder_parameters() -> Result<(), Error> {
+    let ctx = Context::new()?;
+    let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+
+    let quality = encoder.parameter("quality")?;
+    assert_eq!(
+        EncoderParameterKind::Integer {
+            range: Some((0, 100))
+        },
+        quality.kind
+    );
+    assert!(encoder.parameters()?.contains(&quality));
+
+    encoder.set_parameter("quality", EncoderParameterValue::Integer(42))?;
+    assert_eq!(
+        EncoderParameterValue::Integer(42),
+        encoder.get_parameter("quality")?
+    );
+
+    let err = encoder
+        .set_parameter("quality", EncoderParameterValue::Integer(101))
+        .expect_err("out of range");
+    match err {
+        Error::Parameter(ParameterError::InvalidValue { name, .. }) => assert_eq!("quality", name),
+        other => panic!("unexpected error: {:?}", other),
+    }
+
+    assert!(encoder
+        .set_parameter("quality", EncoderParameterValue::Boolean(true))
+        .is_err());
+
+    let err = encoder
+        .set_parameter("no-such-parameter", EncoderParameterValue::Integer(1))
+        .expect_err("unsupported");
+    match err {
+        Error::Parameter(ParameterError::Unsupported(name)) => {
+            assert_eq!("no-such-parameter", name)
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn lossless() -> Result<(), Error> {
+    // subsampled YCbCr doesn't survive exactly, so planar RGB, close enough to grey
+    // that its conversion to YCbCr doesn't clip
+    let rgb = DecoderSettings {
+        chroma: Chroma::C444,
+        colour_space: ColourSpace::Rgb,
+    };
+    let channels = [Channel::R, Channel::G, Channel::B];
+
+    let (width, height) = (64, 48);
+    let mut original = Image::new(width, height, ColourSpace::Rgb, Chroma::C444)?;
+    for (i, &channel) in channels.iter().enumerate() {
+        original.add_plane(channel, width, height, 8)?;
+        let mut plane = original.plane(channel)?;
+        let mut pixels = plane.pixels_mut()?;
+        for y in 0..height {
+            for (x, byte) in pixels.get_row_mut(y)[..width].iter_mut().enumerate() {
+                *byte = (x * 2 + y + i * 16) as u8;
+            }
+        }
+    }
+
+    let ctx = Context::new()?;
+    {
+        let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+        assert!(encoder.set_quality(101).is_err());
+        encoder.set_quality(100)?;
+        encoder.set_lossless(true)?;
+        ctx.encode_image(&original, &mut encoder, &EncodingOptions::default())?;
+    }
+
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+
+    let ctx = Context::from_bytes(&written)?;
+    let handle = ctx.get_primary_image()?;
+    let mut decoded = handle.decode(rgb)?;
+
+    for &channel in &channels {
+        let expected = original.plane(channel)?;
+        let expected_pixels = expected.pixels()?;
+        let actual = decoded.plane(channel)?;
+        let actual_pixels = actual.pixels()?;
+
+        assert_eq!(expected.width(), actual.width());
+        assert_eq!(expected.height(), actual.height());
+        for y in 0..expected.height() {
+            assert_eq!(
+                &expected_pixels.get_row(y)[..expected.width()],
+                &actual_pixels.get_row(y)[..actual.width()],
+                "{:?} row {}",
+                channel,
+                y
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn encode_thumbnails() -> Result<(), Error> {
+    let image = Image::from_rgb8(&gradient(64, 48), 64, 48)?;
+    let small = Image::from_rgb8(&gradient(8, 6), 8, 6)?;
+    let options = EncodingOptions::default();
+
+    let ctx = Context::new()?;
+    {
+        let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+        let master = ctx.encode_image(&image, &mut encoder, &options)?;
+        ctx.set_primary_image(&master)?;
+
+        let thumbnail = ctx.encode_thumbnail(&image, &master, &mut encoder, &options, 16)?;
+        assert!(thumbnail.is_some());
+
+        let small = ctx.encode_image(&small, &mut encoder, &options)?;
+        ctx.assign_thumbnail(&small, &master)?;
+    }
+
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+
+    let ctx = Context::from_bytes(&written)?;
+    let master = ctx.get_primary_image()?;
+    let mut widths = master
+        .thumbnails()?
+        .iter()
+        .map(|thumbnail| thumbnail.width())
+        .collect::<Result<Vec<_>, _>>()?;
+    widths.sort();
+    assert_eq!(vec![8, 16], widths);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn write_metadata() -> Result<(), Error> {
+    let exif = b"MM\0*\0\0\0\x08\0\0";
+    let xmp = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>";
+
+    let image = Image::from_rgb8(&gradient(64, 48), 64, 48)?;
+    let options = EncodingOptions::default();
+
+    let ctx = Context::new()?;
+    {
+        let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+        let handle = ctx.encode_image(&image, &mut encoder, &options)?;
+        ctx.add_exif(&handle, exif)?;
+        ctx.add_xmp(&handle, xmp)?;
+    }
+
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+
+    let source_ctx = Context::from_bytes(&written)?;
+    let source = source_ctx.get_primary_image()?;
+    let check = |handle: &ImageHandle| -> Result<(), Error> {
+        let exif_blocks = handle.metadata(Some("Exif"))?;
+        assert_eq!(1, exif_blocks.len());
+        assert!(exif_blocks[0].data.ends_with(exif));
+
+        let mime_blocks = handle.metadata(Some("mime"))?;
+        assert_eq!(1, mime_blocks.len());
+        assert_eq!("application/rdf+xml", mime_blocks[0].content_type);
+        assert_eq!(&xmp[..], &mime_blocks[0].data[..]);
+        Ok(())
+    };
+    check(&source)?;
+
+    let ctx = Context::new()?;
+    {
+        let decoded = source.decode(DecoderSettings::interleaved_rgb())?;
+        let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+        ctx.encode_image_preserving_metadata(&decoded, &source, &mut encoder, &options)?;
+    }
+
+    let mut rewritten = Vec::new();
+    ctx.write_to(&mut rewritten)?;
+    check(&Context::from_bytes(&rewritten)?.get_primary_image()?)?;
+
+    Ok(())
+}
+
+#[test]
+fn structured_errors() {
+    let err = Context::from_file("no-such-file.heic")
+        .err()
+        .expect("missing file");
+    match err {
+        Error::Heif(err) => {
+            assert_eq!(ErrorCode::InputDoesNotExist, err.code);
+            assert_eq!("heif_context_read_from_file", err.location);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+
+    let err = Context::from_bytes(b"definitely not a heif file")
+        .err()
+        .expect("invalid file");
+    match err {
+        Error::Heif(err) => {
+            assert_eq!(ErrorCode::InvalidInput, err.code);
+            assert_ne!(SubErrorCode::Unspecified, err.subcode);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn errors_are_portable() {
+    fn assert_portable<E: std::error::Error + Send + Sync + 'static>() {}
+    assert_portable::<Error>();
+    assert_portable::<HeifError>();
+    assert_portable::<ParameterError>();
+}
+
+#[test]
+fn owned_handles() -> Result<(), Error> {
+    struct Held {
+        handle: ImageHandle<'static>,
+    }
+
+    let held = {
+        let ctx = Context::from_file("thumbnail.heic")?;
+        Held {
+            handle: ctx.get_primary_image()?,
+        }
+    };
+
+    let thumbnails = held.handle.thumbnails()?;
+    let mut rgb = held.handle.decode(DecoderSettings::interleaved_rgb())?;
+    let mut ycbcr = held.handle.decode(DecoderSettings {
+        chroma: Chroma::C420,
+        colour_space: ColourSpace::YCbCr,
+    })?;
+    drop(held);
+
+    assert_eq!(64, rgb.plane(Channel::Interleaved)?.width());
+    assert_eq!(64, ycbcr.plane(Channel::Y)?.width());
+    assert_eq!(1, thumbnails.len());
+    let mut thumbnail = thumbnails[0].decode(DecoderSettings::interleaved_rgb())?;
+    assert_eq!(32, thumbnail.plane(Channel::Interleaved)?.width());
+
+    Ok(())
+}
+
+#[test]
+fn owned_handles_from_reader() -> Result<(), Error> {
+    let handle = {
+        let ctx = Context::from_reader(io::Cursor::new(fs::read("road.heic")?))?;
+        ctx.get_primary_image()?
+    };
+
+    let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
+    assert_eq!(190, image.plane(Channel::Interleaved)?.height());
+
+    Ok(())
+}
+
+#[test]
+fn threads() -> Result<(), Error> {
+    fn first_row(handle: &ImageHandle) -> Result<Vec<u8>, Error> {
+        let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
+        let plane = image.plane(Channel::Interleaved)?;
+        let row = plane.pixels()?.get_row(0)[..plane.width() * 3].to_vec();
+        Ok(row)
+    }
+
+    let expected = first_row(&Context::from_file("road.heic")?.get_primary_image()?)?;
+    let shared = Context::from_file("road.heic")?;
+
+    let workers: Vec<_> = (0..8)
+        .map(|worker| {
+            let shared = shared.clone();
+            thread::spawn(move || -> Result<Vec<Vec<u8>>, Error> {
+                let mut rows = Vec::new();
+                for _ in 0..4 {
+                    // alternate between parallel work, and contending for one context
+                    let handle = if 0 == worker % 2 {
+                        Context::from_file("road.heic")?.get_primary_image()?
+                    } else {
+                        shared.get_primary_image()?
+                    };
+                    rows.push(first_row(&handle)?);
+                }
+                Ok(rows)
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        for row in worker.join().expect("no panic")? {
+            assert_eq!(expected, row);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn foreign_handles() -> Result<(), Error> {
+    let source = Context::from_file("road.heic")?;
+    let handle = source.get_primary_image()?;
+
+    let ctx = Context::new()?;
+    match ctx.set_primary_image(&handle) {
+        Err(Error::InvalidArgument(_)) => (),
+        other => panic!("unexpected: {:?}", other),
+    }
+
+    source.set_primary_image(&handle)?;
+
+    Ok(())
+}
+
+#[test]
+fn typed_pixels() -> Result<(), Error> {
+    let handle = Context::from_file("road.heic")?.get_primary_image()?;
+
+    let mut image = handle.decode(DecoderSettings::interleaved_rgb())?;
+    let plane = image.plane(Channel::Interleaved)?;
+    let pixels = plane.pixels()?;
+    let x = plane.width() - 1;
+    let y = plane.height() - 1;
+    assert_eq!(
+        &pixels.get_row(y)[x * 3..x * 3 + 3],
+        &pixels.get_rgb(x, y)[..]
+    );
+
+    let mut image = handle.decode(DecoderSettings {
+        chroma: Chroma::C420,
+        colour_space: ColourSpace::YCbCr,
+    })?;
+    let plane = image.plane(Channel::Cb)?;
+    let pixels = plane.pixels()?;
+    let x = plane.width() - 1;
+    assert_eq!(pixels.get_row(1)[x], pixels.get_u8(x, 1));
+
+    let handle = Context::from_file("alpha.heic")?.get_primary_image()?;
+    let mut image = handle.decode(DecoderSettings::interleaved_rgba())?;
+    let plane = image.plane(Channel::Interleaved)?;
+    let pixels = plane.pixels()?;
+    assert_eq!(0xff, pixels.get_rgba(0, 0)[3]);
+    assert_eq!(0x00, pixels.get_rgba(plane.width() - 1, 0)[3]);
+
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "left < right")]
+fn typed_pixels_out_of_bounds() {
+    let handle = Context::from_file("road.heic")
+        .and_then(|ctx| ctx.get_primary_image())
+        .expect("road");
+    let mut image = handle
+        .decode(DecoderSettings::interleaved_rgb())
+        .expect("decode");
+    let plane = image.plane(Channel::Interleaved).expect("plane");
+    plane.pixels().expect("pixels").get_rgb(plane.width(), 0);
+}
+
+#[test]
+#[should_panic(expected = "bits per pixel")]
+fn typed_pixels_wrong_layout() {
+    let handle = Context::from_file("road.heic")
+        .and_then(|ctx| ctx.get_primary_image())
+        .expect("road");
+    let mut image = handle
+        .decode(DecoderSettings::interleaved_rgb())
+        .expect("decode");
+    let plane = image.plane(Channel::Interleaved).expect("plane");
+    plane.pixels().expect("pixels").get_u16(0, 0);
+}
+
+#[test]
+fn high_bit_depth() -> Result<(), Error> {
+    // 64x64, with ten-bit luma increasing by 16 per column
+    let handle = Context::from_file("ten-bit.heic")?.get_primary_image()?;
+    let mut image = handle.decode(DecoderSettings {
+        chroma: Chroma::C420,
+        colour_space: ColourSpace::YCbCr,
+    })?;
+
+    assert!(image.has_channel(Channel::Cb));
+    assert!(!image.has_channel(Channel::Alpha));
+    assert!(image.bits_per_pixel(Channel::Alpha).is_err());
+    let bits = image.bits_per_pixel(Channel::Y)?;
+    assert!(bits > 8 && bits <= 16, "{}", bits);
+
+    let plane = image.plane(Channel::Y)?;
+    assert_eq!(bits, plane.bits_per_pixel());
+    let pixels = plane.pixels()?;
+    for y in 0..plane.height() {
+        let row = pixels.get_row_u16(y);
+        assert_eq!(plane.width(), row.len());
+        for (x, &value) in row.iter().enumerate() {
+            assert!(value < 1 << 10);
+            assert_eq!(value, pixels.get_u16(x, y));
+            let expected = x as i32 * 16;
+            assert!((i32::from(value) - expected).abs() <= 16, "({}, {})", x, y);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn edit_pixels() -> Result<(), Error> {
+    let source = Context::from_file("road.heic")?.get_primary_image()?;
+    let mut image = source.decode(DecoderSettings::interleaved_rgb())?;
+    {
+        let mut plane = image.plane(Channel::Interleaved)?;
+        let width = plane.width();
+        let mut pixels = plane.pixels_mut()?;
+        for y in 0..64 {
+            for byte in &mut pixels.get_row_mut(y)[..width * 3] {
+                *byte = 0;
+            }
+        }
+        assert_eq!(0, pixels.get_row(63)[width * 3 - 1]);
+    }
+
+    let ctx = Context::new()?;
+    let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+    ctx.encode_image(&image, &mut encoder, &EncodingOptions::default())?;
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+
+    let mut redacted = Context::from_bytes(&written)?
+        .get_primary_image()?
+        .decode(DecoderSettings::interleaved_rgb())?;
+    let plane = redacted.plane(Channel::Interleaved)?;
+    let pixels = plane.pixels()?;
+    for y in 8..56 {
+        for x in 8..plane.width() - 8 {
+            let [r, g, b] = pixels.get_rgb(x, y);
+            assert!(r < 16 && g < 16 && b < 16, "({}, {})", x, y);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "x265")]
+fn images_from_buffers() -> Result<(), Error> {
+    // an odd width, so rows are padded
+    let (width, height) = (13, 7);
+    let rgb = gradient(width, height);
+    let mut image = Image::from_rgb8(&rgb, width, height)?;
+    {
+        let plane = image.plane(Channel::Interleaved)?;
+        let pixels = plane.pixels()?;
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) * 3;
+                assert_eq!(&rgb[i..i + 3], &pixels.get_rgb(x, y)[..]);
+            }
+        }
+    }
+
+    let rgba: Vec<u8> = rgb
+        .chunks(3)
+        .flat_map(|p| vec![p[0], p[1], p[2], p[0]])
+        .collect();
+    let mut image = Image::from_rgba8(&rgba, width, height)?;
+    {
+        let plane = image.plane(Channel::Interleaved)?;
+        let pixels = plane.pixels()?;
+        assert_eq!(
+            &rgba[rgba.len() - 4..],
+            &pixels.get_rgba(width - 1, height - 1)[..]
+        );
+    }
+
+    assert!(Image::from_rgb8(&rgb, width, height + 1).is_err());
+    assert!(Image::from_rgba8(&rgb, width, height).is_err());
+    assert!(Image::from_rgb8(&[], 0, 0).is_err());
+    match Image::from_rgb8(&rgb, usize::MAX / 2, height) {
+        Err(Error::InvalidArgument(_)) => (),
+        other => panic!("unexpected: {:?}", other.map(|_| ())),
+    }
+
+    let mut image = Image::new(64, 48, ColourSpace::YCbCr, Chroma::C420)?;
+    image.add_plane(Channel::Y, 64, 48, 8)?;
+    image.add_plane(Channel::Cb, 32, 24, 8)?;
+    image.add_plane(Channel::Cr, 32, 24, 8)?;
+    for &channel in &[Channel::Y, Channel::Cb, Channel::Cr] {
+        let mut plane = image.plane(channel)?;
+        let (width, height) = (plane.width(), plane.height());
+        let mut pixels = plane.pixels_mut()?;
+        for y in 0..height {
+            for byte in &mut pixels.get_row_mut(y)[..width] {
+                *byte = 0x80;
+            }
+        }
+    }
+
+    let ctx = Context::new()?;
+    let mut encoder = ctx.encoder_for_format(CompressionFormat::Hevc)?;
+    ctx.encode_image(&image, &mut encoder, &EncodingOptions::default())?;
+    let mut written = Vec::new();
+    ctx.write_to(&mut written)?;
+
+    let handle = Context::from_bytes(&written)?.get_primary_image()?;
+    assert_eq!(64, handle.width()?);
+    assert_eq!(48, handle.height()?);
+
+    Ok(())
+}
+
+#[test]
+fn scale() -> Result<(), Error> {
+    let handle = Context::from_file("road.heic")?.get_primary_image()?;
+
+    let rgb = handle.decode(DecoderSettings::interleaved_rgb())?;
+    let mut small = rgb.scale(180, 95)?;
+    drop(rgb);
+    assert!(small.has_channel(Channel::Interleaved));
+    let plane = small.plane(Channel::Interleaved)?;
+    assert_eq!((180, 95), (plane.width(), plane.height()));
+    plane.pixels()?.get_rgb(179, 94);
+
+    let ycbcr = handle.decode(DecoderSettings {
+        chroma: Chroma::C420,
+        colour_space: ColourSpace::YCbCr,
+    })?;
+    let mut large = ycbcr.scale(720, 380)?;
+    for &(channel, width, height) in &[
+        (Channel::Y, 720, 380),
+        (Channel::Cb, 360, 190),
+        (Channel::Cr, 360, 190),
+    ] {
+        let plane = large.plane(channel)?;
+        assert_eq!((width, height), (plane.width(), plane.height()));
+    }
+    assert!(!large.has_channel(Channel::Interleaved));
+
+    Ok(())
+}
+
+#[test]
+fn ignore_transformations() -> Result<(), Error> {
+    // coded as 64x48, with an instruction to rotate it by 90 degrees
+    let handle = Context::from_file("rotated.heic")?.get_primary_image()?;
+    let settings = DecoderSettings::interleaved_rgb();
+
+    let mut rotated = handle.decode(settings)?;
+    let plane = rotated.plane(Channel::Interleaved)?;
+    assert_eq!((48, 64), (plane.width(), plane.height()));
+
+    let options = DecodingOptions {
+        ignore_transformations: true,
+    };
+    let mut coded = handle.decode_with_options(settings, &options)?;
+    let plane = coded.plane(Channel::Interleaved)?;
+    assert_eq!((64, 48), (plane.width(), plane.height()));
+
+    Ok(())
+}